@@ -0,0 +1,104 @@
+#![feature(test)]
+
+extern crate test;
+
+use reina::disk::{DiskManager, PageId};
+use reina::store::PageStore;
+
+use std::path::PathBuf;
+
+use test::Bencher;
+
+const PAGE_SIZE: u64 = 4096;
+const PAGES: u64 = 1024;
+
+// A heap file of `PAGES` written pages, removed on drop.
+struct HeapFile(PathBuf);
+
+impl HeapFile {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("reina-bench-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let disk = DiskManager::from_path(&path, PAGE_SIZE).unwrap();
+        for i in 0..PAGES {
+            let page_id = disk.allocate_page().unwrap();
+            disk.write_page(page_id, &vec![i as u8; PAGE_SIZE as usize])
+                .unwrap();
+        }
+        disk.sync().unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for HeapFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Visits every page once in a scattered order.
+fn random_reads<S: PageStore>(b: &mut Bencher, disk: &DiskManager<S>) {
+    let mut page = vec![0; PAGE_SIZE as usize];
+    let mut i = 0;
+    b.bytes = PAGE_SIZE;
+    b.iter(|| {
+        i = (i + 389) % PAGES;
+        disk.read_page(PageId(i + 1), &mut page).unwrap();
+    });
+}
+
+fn sequential_reads<S: PageStore>(b: &mut Bencher, disk: &DiskManager<S>) {
+    let mut page = vec![0; PAGE_SIZE as usize];
+    let mut i = 0;
+    b.bytes = PAGE_SIZE;
+    b.iter(|| {
+        i = (i + 1) % PAGES;
+        disk.read_page(PageId(i + 1), &mut page).unwrap();
+    });
+}
+
+fn writes<S: PageStore>(b: &mut Bencher, disk: &DiskManager<S>) {
+    let page = vec![0x5a; PAGE_SIZE as usize];
+    let mut i = 0;
+    b.bytes = PAGE_SIZE;
+    b.iter(|| {
+        i = (i + 389) % PAGES;
+        disk.write_page(PageId(i + 1), &page).unwrap();
+    });
+}
+
+#[bench]
+fn pread_random_reads(b: &mut Bencher) {
+    let file = HeapFile::new("pread-random");
+    random_reads(b, &DiskManager::open(&file.0).unwrap());
+}
+
+#[bench]
+fn mmap_random_reads(b: &mut Bencher) {
+    let file = HeapFile::new("mmap-random");
+    random_reads(b, &DiskManager::open_mapped(&file.0).unwrap());
+}
+
+#[bench]
+fn pread_sequential_reads(b: &mut Bencher) {
+    let file = HeapFile::new("pread-sequential");
+    sequential_reads(b, &DiskManager::open(&file.0).unwrap());
+}
+
+#[bench]
+fn mmap_sequential_reads(b: &mut Bencher) {
+    let file = HeapFile::new("mmap-sequential");
+    sequential_reads(b, &DiskManager::open_mapped(&file.0).unwrap());
+}
+
+#[bench]
+fn pwrite_writes(b: &mut Bencher) {
+    let file = HeapFile::new("pwrite");
+    writes(b, &DiskManager::open(&file.0).unwrap());
+}
+
+#[bench]
+fn mmap_writes(b: &mut Bencher) {
+    let file = HeapFile::new("mmap-writes");
+    writes(b, &DiskManager::open_mapped(&file.0).unwrap());
+}
@@ -1,17 +1,49 @@
-use crate::disk::{DiskManager, PageId};
+use crate::disk::{DiskManager, DiskManagerError, DiskStats, PageId};
+use crate::page::{self, PageBuf};
+use crate::store::{FileStore, PageStore};
+use crate::wal::{LogFlush, Lsn, WalError};
 
-use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Weak};
 
+use lru::LruCache;
+use parking_lot::lock_api::{ArcRwLockReadGuard, ArcRwLockWriteGuard};
+use parking_lot::{Mutex, RawRwLock, RwLock, RwLockReadGuard};
 use thiserror::Error;
 
-pub type Page = Vec<u8>; // length is PAGE_SIZE
+use read_ahead::ReadAhead;
+
+mod arc;
+mod lru_k;
+mod read_ahead;
+mod two_q;
+mod writer;
+
+pub use arc::{AdaptiveReplacement, AdaptiveReplacementError};
+pub use lru_k::{LruK, LruKError};
+pub use read_ahead::ReadAheadOptions;
+pub use two_q::{TwoQueue, TwoQueueError};
+pub(crate) use writer::Periodic;
+pub use writer::{BackgroundWriter, BackgroundWriterOptions};
+
+pub type Page = PageBuf; // length is PAGE_SIZE
 
 pub struct InnerBufferFrame {
     page_id: PageId,
-    page: RefCell<Page>,
-    is_dirty: Cell<bool>,
+    page: Arc<RwLock<Page>>,
+    is_dirty: AtomicBool,
+    // LSN of the first logged change since the page was last clean, or 0.
+    rec_lsn: AtomicU64,
+    pin_count: AtomicUsize,
+    // Held while the page is written back, so that write-backs of a frame
+    // never overlap and a frame can be cleaned without pinning it.
+    write_back: Mutex<()>,
+    // Set while the page is read in under its write latch, and if that read
+    // failed and the frame was dropped from the pool.
+    loading: AtomicBool,
+    load_failed: AtomicBool,
 }
 
 impl std::fmt::Debug for InnerBufferFrame {
@@ -19,16 +51,20 @@ impl std::fmt::Debug for InnerBufferFrame {
         f.debug_struct("InnerBufferFrame")
             .field("page_id", &self.page_id)
             .field("is_dirty", &self.is_dirty)
+            .field("pin_count", &self.pin_count)
             .finish()
     }
 }
 
 impl PartialEq for InnerBufferFrame {
     fn eq(&self, other: &Self) -> bool {
-        self.page_id == other.page_id && self.is_dirty == other.is_dirty
+        self.page_id == other.page_id
+            && self.is_dirty.load(Ordering::Acquire) == other.is_dirty.load(Ordering::Acquire)
     }
 }
 
+// A handle to a frame. Cloning it does not pin the frame; callers outside the
+// pool hold a `PageGuard`, `ReadPageGuard` or `WritePageGuard` instead.
 #[derive(Debug, PartialEq)]
 pub struct BufferFrame {
     inner: Arc<InnerBufferFrame>,
@@ -39,26 +75,80 @@ impl BufferFrame {
         Self {
             inner: Arc::new(InnerBufferFrame {
                 page_id,
-                page: RefCell::new(page),
-                is_dirty: Cell::new(false),
+                page: Arc::new(RwLock::new(page)),
+                is_dirty: AtomicBool::new(false),
+                rec_lsn: AtomicU64::new(0),
+                pin_count: AtomicUsize::new(0),
+                write_back: Mutex::new(()),
+                loading: AtomicBool::new(false),
+                load_failed: AtomicBool::new(false),
             }),
         }
     }
+    // A pinned, zeroed frame that stays write-latched, through the returned
+    // guard, until its page has been read in; see `finish_load`.
+    fn loading(page_id: PageId, page_size: usize) -> (Self, ArcRwLockWriteGuard<RawRwLock, Page>) {
+        let frame = Self::new(page_id, PageBuf::zeroed(page_size));
+        frame.inner.loading.store(true, Ordering::Release);
+        let page = frame.inner.page.write_arc();
+        frame.pin();
+        (frame, page)
+    }
+    // Waits for a pinned frame that may still be loading. Returns false, with
+    // the frame unpinned, if the load failed and the frame left the pool.
+    fn wait_for_load(&self) -> bool {
+        if self.inner.loading.load(Ordering::Acquire) {
+            drop(self.get_page_ref());
+        }
+        if self.inner.load_failed.load(Ordering::Acquire) {
+            self.unpin();
+            return false;
+        }
+        true
+    }
     pub fn page_id(&self) -> PageId {
         self.inner.page_id
     }
-    pub fn get_page_ref(&self) -> std::cell::Ref<Page> {
-        self.inner.page.borrow()
+    pub fn get_page_ref(&self) -> RwLockReadGuard<'_, Page> {
+        self.inner.page.read()
     }
-    pub fn get_page_mut(&self) -> std::cell::RefMut<Page> {
-        self.inner.is_dirty.set(true);
-        self.inner.page.borrow_mut()
+    pub(crate) fn try_get_page_ref(&self) -> Option<RwLockReadGuard<'_, Page>> {
+        self.inner.page.try_read()
     }
     pub fn is_dirty(&self) -> bool {
-        self.inner.is_dirty.get()
+        self.inner.is_dirty.load(Ordering::Acquire)
+    }
+    pub(crate) fn set_dirty(&self, dirty: bool) {
+        if !dirty {
+            self.inner.rec_lsn.store(0, Ordering::Release);
+        }
+        self.inner.is_dirty.store(dirty, Ordering::Release);
+    }
+    pub fn rec_lsn(&self) -> Lsn {
+        Lsn(self.inner.rec_lsn.load(Ordering::Acquire))
+    }
+    fn record_change(&self, lsn: Lsn) {
+        let _ = self
+            .inner
+            .rec_lsn
+            .compare_exchange(0, lsn.0, Ordering::AcqRel, Ordering::Acquire);
+    }
+    pub fn pin_count(&self) -> usize {
+        self.inner.pin_count.load(Ordering::Acquire)
+    }
+    pub fn is_pinned(&self) -> bool {
+        self.pin_count() > 0
     }
-    pub fn is_unique(&self) -> bool {
-        Arc::strong_count(&self.inner) == 1 && Arc::weak_count(&self.inner) == 0
+    pub(crate) fn pin(&self) {
+        self.inner.pin_count.fetch_add(1, Ordering::AcqRel);
+    }
+    pub(crate) fn unpin(&self) {
+        let previous = self.inner.pin_count.fetch_sub(1, Ordering::AcqRel);
+        debug_assert!(
+            previous > 0,
+            "unpinning unpinned frame {:?}",
+            self.page_id()
+        );
     }
 }
 
@@ -70,18 +160,199 @@ impl Clone for BufferFrame {
     }
 }
 
-pub trait PoolAlgorithm {
-    type Hint;
-    type PushError: std::fmt::Debug;
+// Keeps a page pinned in the pool without latching it.
+#[derive(Debug)]
+pub struct PageGuard {
+    frame: BufferFrame,
+}
+
+impl PageGuard {
+    // `frame` must already be pinned on behalf of the guard.
+    fn new(frame: BufferFrame) -> Self {
+        Self { frame }
+    }
+    pub fn page_id(&self) -> PageId {
+        self.frame.page_id()
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.frame.is_dirty()
+    }
+    pub fn read(self) -> ReadPageGuard {
+        let page = self.frame.inner.page.read_arc();
+        ReadPageGuard {
+            guard: self,
+            page: Some(page),
+        }
+    }
+    pub fn write(self) -> WritePageGuard {
+        let page = self.frame.inner.page.write_arc();
+        WritePageGuard {
+            guard: self,
+            page: Some(page),
+        }
+    }
+}
+
+impl Drop for PageGuard {
+    fn drop(&mut self) {
+        self.frame.unpin();
+    }
+}
+
+// A pinned page under a shared latch.
+pub struct ReadPageGuard {
+    guard: PageGuard,
+    page: Option<ArcRwLockReadGuard<RawRwLock, Page>>,
+}
+
+impl ReadPageGuard {
+    pub fn page_id(&self) -> PageId {
+        self.guard.page_id()
+    }
+}
+
+impl std::ops::Deref for ReadPageGuard {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        self.page.as_ref().unwrap()
+    }
+}
+
+impl Drop for ReadPageGuard {
+    fn drop(&mut self) {
+        // Release the latch before `guard` unpins the frame.
+        self.page.take();
+    }
+}
+
+// A pinned page under an exclusive latch. The page is marked dirty the first
+// time it is borrowed mutably.
+pub struct WritePageGuard {
+    guard: PageGuard,
+    page: Option<ArcRwLockWriteGuard<RawRwLock, Page>>,
+}
+
+impl WritePageGuard {
+    pub fn page_id(&self) -> PageId {
+        self.guard.page_id()
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.guard.is_dirty()
+    }
+    pub fn page_lsn(&self) -> Lsn {
+        page::page_lsn(self)
+    }
+    // LSN of the first logged change since the page was last written back,
+    // or `Lsn::INVALID`.
+    pub fn rec_lsn(&self) -> Lsn {
+        self.guard.frame.rec_lsn()
+    }
+    // Records that the page now reflects the log record at `lsn`; the page
+    // is not written out before that record is durable.
+    pub fn set_page_lsn(&mut self, lsn: Lsn) {
+        debug_assert!(lsn >= self.page_lsn(), "page LSN moved backwards");
+        page::set_page_lsn(self, lsn);
+        self.guard.frame.record_change(lsn);
+    }
+}
+
+impl std::ops::Deref for WritePageGuard {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        self.page.as_ref().unwrap()
+    }
+}
+
+impl std::ops::DerefMut for WritePageGuard {
+    fn deref_mut(&mut self) -> &mut Page {
+        self.guard.frame.set_dirty(true);
+        self.page.as_mut().unwrap()
+    }
+}
+
+impl Drop for WritePageGuard {
+    fn drop(&mut self) {
+        self.page.take();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PushOutcome {
+    Inserted,
+    Evicted { page_id: PageId, frame: BufferFrame },
+    // Every resident frame is pinned; the pushed frame was not inserted.
+    Full,
+}
+
+// How the caller expects to use a page. Policies may ignore hints they have no
+// use for; `Random` is the plain, unhinted access.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessHint {
+    #[default]
+    Random,
+    // Part of a scan; the page should not displace the working set.
+    Sequential,
+    // Touched once and not again soon.
+    OneShot,
+    // Should survive eviction pressure longer than usual.
+    KeepHot,
+}
+
+// Lookups seen by a policy, for comparing policies on the same workload.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PolicyCounters {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl PolicyCounters {
+    pub(crate) fn record(&mut self, hit: bool) {
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+    }
+}
+
+// The least recently used frame in `list` that can be evicted.
+fn lru_unpinned(list: &LruCache<PageId, BufferFrame>) -> Option<PageId> {
+    list.iter()
+        .rev()
+        .find(|(_, frame)| !frame.is_pinned())
+        .map(|(&page_id, _)| page_id)
+}
+
+pub trait PoolAlgorithm: Send {
+    type Hint: Copy + Default;
+    type PushError: std::error::Error + Send + Sync + 'static;
 
     fn new(size_hint: Option<usize>) -> Self;
     fn request_with_hint(&mut self, hint: Self::Hint, page_id: PageId) -> Option<BufferFrame>;
     fn request(&mut self, page_id: PageId) -> Option<BufferFrame>;
-    fn push(
+    // Looks up a resident frame without counting it as an access.
+    fn peek(&self, page_id: PageId) -> Option<&BufferFrame>;
+    fn frames(&self) -> impl Iterator<Item = &BufferFrame>;
+    // Up to `limit` resident frames in roughly the order they would be
+    // evicted, pinned or not.
+    fn eviction_order(&self, limit: usize) -> impl Iterator<Item = &BufferFrame>;
+    fn remove(&mut self, page_id: PageId) -> Option<BufferFrame>;
+    fn push(&mut self, page_id: PageId, frame: BufferFrame)
+        -> Result<PushOutcome, Self::PushError>;
+    fn push_with_hint(
         &mut self,
+        _hint: Self::Hint,
         page_id: PageId,
         frame: BufferFrame,
-    ) -> Result<(PageId, BufferFrame), Self::PushError>;
+    ) -> Result<PushOutcome, Self::PushError> {
+        self.push(page_id, frame)
+    }
+    fn counters(&self) -> PolicyCounters;
+    // How strongly the policy wants to keep a resident page; higher values are
+    // evicted later. The scale is policy specific.
+    fn usage(&self, page_id: PageId) -> Option<u64>;
 }
 
 #[derive(Debug)]
@@ -89,25 +360,58 @@ pub struct ClockSweep {
     pub(crate) clock_hand: usize,
     frames: Vec<(u64, BufferFrame)>, // (counter, frame)
     map: HashMap<PageId, usize>,     // page_id -> index
+    // Pages loaded by sequential scans, oldest first. Once the ring is full a
+    // scan recycles its own frames instead of sweeping the whole pool.
+    ring: VecDeque<PageId>,
+    ring_size: usize,
+    counters: PolicyCounters,
 }
 
+const RING_SIZE: usize = 32;
+const KEEP_HOT_USAGE: u64 = 5;
+
 #[derive(Error, Debug, PartialEq)]
 pub enum ClockSweepError {
-    #[error("success")]
-    Success,
-
-    #[error("pool is full")]
-    PoolIsFull,
+    #[error("page {0:?} is already resident")]
+    AlreadyResident(PageId),
 }
 
 impl ClockSweep {
     fn next_clock_hand(&self) -> usize {
         (self.clock_hand + 1) % self.frames.len()
     }
+
+    // Replaces the oldest ring page that nobody else has used since the scan
+    // loaded it. Pages that were pinned or referenced again leave the ring.
+    fn recycle_ring(&mut self, page_id: PageId, frame: BufferFrame) -> Option<PushOutcome> {
+        if self.ring.len() < self.ring_size || self.frames.len() < self.frames.capacity() {
+            return None;
+        }
+
+        while let Some(old_page_id) = self.ring.pop_front() {
+            let Some(&index) = self.map.get(&old_page_id) else {
+                continue;
+            };
+            let (counter, old_frame) = &self.frames[index];
+            if *counter > 0 || old_frame.is_pinned() {
+                continue;
+            }
+
+            self.map.remove(&old_page_id);
+            let (_, old_frame) = core::mem::replace(&mut self.frames[index], (0, frame));
+            self.map.insert(page_id, index);
+            self.ring.push_back(page_id);
+            return Some(PushOutcome::Evicted {
+                page_id: old_page_id,
+                frame: old_frame,
+            });
+        }
+        None
+    }
 }
 
 impl PoolAlgorithm for ClockSweep {
-    type Hint = ();
+    type Hint = AccessHint;
     type PushError = ClockSweepError;
 
     fn new(size_hint: Option<usize>) -> Self {
@@ -116,50 +420,85 @@ impl PoolAlgorithm for ClockSweep {
             clock_hand: 0,
             frames: Vec::with_capacity(size),
             map: HashMap::with_capacity(size),
+            ring: VecDeque::new(),
+            ring_size: (size / 8).clamp(1, RING_SIZE),
+            counters: PolicyCounters::default(),
         }
     }
 
-    fn request_with_hint(&mut self, _hint: Self::Hint, page_id: PageId) -> Option<BufferFrame> {
-        self.request(page_id)
+    fn request_with_hint(&mut self, hint: Self::Hint, page_id: PageId) -> Option<BufferFrame> {
+        let index = self.map.get(&page_id).copied();
+        self.counters.record(index.is_some());
+        let (counter, frame) = &mut self.frames[index?];
+        match hint {
+            AccessHint::Random => *counter += 1,
+            AccessHint::Sequential | AccessHint::OneShot => {}
+            AccessHint::KeepHot => *counter = (*counter + 1).max(KEEP_HOT_USAGE),
+        }
+        Some(frame.clone())
     }
 
     fn request(&mut self, page_id: PageId) -> Option<BufferFrame> {
-        if let Some(&index) = self.map.get(&page_id) {
-            let (counter, frame) = &mut self.frames[index];
-            *counter += 1;
-            Some(frame.clone())
-        } else {
-            None
+        self.request_with_hint(AccessHint::Random, page_id)
+    }
+
+    fn peek(&self, page_id: PageId) -> Option<&BufferFrame> {
+        self.map.get(&page_id).map(|&index| &self.frames[index].1)
+    }
+
+    fn frames(&self) -> impl Iterator<Item = &BufferFrame> {
+        self.frames.iter().map(|(_, frame)| frame)
+    }
+
+    fn eviction_order(&self, limit: usize) -> impl Iterator<Item = &BufferFrame> {
+        let len = self.frames.len();
+        (0..limit.min(len)).map(move |i| &self.frames[(self.clock_hand + i) % len].1)
+    }
+
+    fn remove(&mut self, page_id: PageId) -> Option<BufferFrame> {
+        let index = self.map.remove(&page_id)?;
+        let (_, frame) = self.frames.swap_remove(index);
+        if let Some((_, moved)) = self.frames.get(index) {
+            self.map.insert(moved.page_id(), index);
+        }
+        if self.clock_hand >= self.frames.len() {
+            self.clock_hand = 0;
         }
+        self.ring.retain(|&id| id != page_id);
+        Some(frame)
     }
 
     fn push(
         &mut self,
         page_id: PageId,
         frame: BufferFrame,
-    ) -> Result<(PageId, BufferFrame), Self::PushError> {
+    ) -> Result<PushOutcome, Self::PushError> {
+        if self.map.contains_key(&page_id) {
+            return Err(ClockSweepError::AlreadyResident(page_id));
+        }
+
         if self.frames.len() < self.frames.capacity() {
             let index = self.frames.len();
             self.frames.push((0, frame.clone()));
             self.map.insert(page_id, index);
-            return Err(ClockSweepError::Success);
+            return Ok(PushOutcome::Inserted);
         }
 
         let mut consecutive_fail = 0;
 
         let (buf_idx, remove_page_id) = loop {
             let (counter, frame) = &mut self.frames[self.clock_hand];
-            if counter == &0 {
-                break (self.clock_hand, frame.page_id());
-            }
+            if !frame.is_pinned() {
+                if counter == &0 {
+                    break (self.clock_hand, frame.page_id());
+                }
 
-            if frame.is_unique() {
                 consecutive_fail = 0;
                 *counter -= 1;
             } else {
                 consecutive_fail += 1;
                 if consecutive_fail >= self.frames.len() {
-                    return Err(ClockSweepError::PoolIsFull);
+                    return Ok(PushOutcome::Full);
                 }
             }
 
@@ -169,62 +508,754 @@ impl PoolAlgorithm for ClockSweep {
         let old_idx = self.map.remove(&remove_page_id).unwrap();
         let (_, old_frame) = core::mem::replace(&mut self.frames[old_idx], (0, frame));
         self.map.insert(page_id, buf_idx);
+        self.ring.retain(|&id| id != remove_page_id);
+
+        Ok(PushOutcome::Evicted {
+            page_id: remove_page_id,
+            frame: old_frame,
+        })
+    }
+
+    fn push_with_hint(
+        &mut self,
+        hint: Self::Hint,
+        page_id: PageId,
+        frame: BufferFrame,
+    ) -> Result<PushOutcome, Self::PushError> {
+        if self.map.contains_key(&page_id) {
+            return Err(ClockSweepError::AlreadyResident(page_id));
+        }
+
+        match hint {
+            AccessHint::Random | AccessHint::OneShot => self.push(page_id, frame),
+            AccessHint::Sequential => {
+                if let Some(outcome) = self.recycle_ring(page_id, frame.clone()) {
+                    return Ok(outcome);
+                }
+                let outcome = self.push(page_id, frame)?;
+                if outcome != PushOutcome::Full {
+                    self.ring.push_back(page_id);
+                }
+                Ok(outcome)
+            }
+            AccessHint::KeepHot => {
+                let outcome = self.push(page_id, frame)?;
+                if let Some(&index) = self.map.get(&page_id) {
+                    self.frames[index].0 = KEEP_HOT_USAGE;
+                }
+                Ok(outcome)
+            }
+        }
+    }
+
+    fn counters(&self) -> PolicyCounters {
+        self.counters
+    }
 
-        return Ok((remove_page_id, old_frame));
+    fn usage(&self, page_id: PageId) -> Option<u64> {
+        self.map.get(&page_id).map(|&index| self.frames[index].0)
     }
 }
 
-pub struct BufferPoolManager<Alg: PoolAlgorithm> {
-    disk_manager: DiskManager,
-    pool: Alg,
+#[derive(Error, Debug)]
+pub enum BufferPoolError {
+    #[error(transparent)]
+    Disk(#[from] DiskManagerError),
+
+    #[error(transparent)]
+    Wal(#[from] WalError),
+
+    #[error("page {0:?} is pinned")]
+    PagePinned(PageId),
+
+    #[error("every frame in the pool is pinned")]
+    PoolExhausted,
+
+    #[error("replacement policy error: {0}")]
+    Policy(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+// The page table is latched as a whole by `pool`, which is never held across
+// I/O. A miss inserts a pinned frame that stays write-latched until the page is
+// read, so a page is never loaded twice; fetches that hit it wait on the latch.
+// Victims move to `evicting` until they are written back, and a miss on such a
+// page takes the frame back instead of reading a stale copy from disk. Page
+// contents are latched per frame. Neither the pool latch nor the latch of a
+// page being loaded is ever held while waiting for another page latch.
+//
+// With a log attached, a dirty page is only written once the log is durable up
+// to its page LSN.
+pub struct BufferPoolManager<Alg: PoolAlgorithm, S: PageStore = FileStore> {
+    disk_manager: DiskManager<S>,
+    pool: Mutex<Alg>,
+    evicting: Mutex<HashMap<PageId, BufferFrame>>,
+    stats: PoolStats,
+    read_ahead: Option<ReadAhead>,
+    wal: Option<Arc<dyn LogFlush>>,
+    closed: bool,
 }
 
-impl<Alg: PoolAlgorithm> BufferPoolManager<Alg> {
-    pub fn new(disk_manager: DiskManager, pool_size: usize) -> Self {
+// Most pages admitted in one batch, and under one hold of the pool latch, by
+// `prefetch` and read-ahead.
+const PREFETCH_BATCH: usize = 32;
+
+#[derive(Debug, Default)]
+struct PoolStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    write_backs: AtomicU64,
+    pool_full: AtomicU64,
+    prefetched: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BufferPoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // Dirty pages written to disk, whether on eviction or by a flush.
+    pub write_backs: u64,
+    // Requests that failed with `PoolExhausted`.
+    pub pool_full: u64,
+    // Pages loaded by `prefetch` or read-ahead rather than by a fetch.
+    pub prefetched: u64,
+    pub disk: DiskStats,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResidentPage {
+    pub page_id: PageId,
+    pub pin_count: usize,
+    pub usage: u64,
+    pub is_dirty: bool,
+}
+
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl<Alg: PoolAlgorithm, S: PageStore> BufferPoolManager<Alg, S> {
+    pub fn new(disk_manager: DiskManager<S>, pool_size: usize) -> Self {
+        Self::with_pool(disk_manager, Alg::new(Some(pool_size)))
+    }
+
+    pub fn with_pool(disk_manager: DiskManager<S>, pool: Alg) -> Self {
         Self {
             disk_manager,
-            pool: Alg::new(Some(pool_size)),
+            pool: Mutex::new(pool),
+            evicting: Mutex::new(HashMap::new()),
+            stats: PoolStats::default(),
+            read_ahead: None,
+            wal: None,
+            closed: false,
+        }
+    }
+
+    // Attaches the log that dirty pages wait for before they are written.
+    pub fn with_wal(mut self, wal: Arc<dyn LogFlush>) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    // Starts a worker thread that loads pages for `prefetch` and for
+    // sequential scans. The worker exits once the pool is dropped.
+    pub fn with_read_ahead(mut self, options: ReadAheadOptions) -> Arc<Self>
+    where
+        Alg: 'static,
+        S: 'static,
+    {
+        Arc::new_cyclic(|bpm: &Weak<Self>| {
+            let (sender, receiver) = mpsc::channel::<Range<u64>>();
+            let bpm = bpm.clone();
+            std::thread::spawn(move || {
+                for pages in receiver {
+                    let Some(bpm) = bpm.upgrade() else {
+                        break;
+                    };
+                    bpm.load_pages(pages);
+                }
+            });
+
+            self.read_ahead = Some(ReadAhead::new(options, sender));
+            self
+        })
+    }
+
+    // Loads the pages into the pool without pinning them, so that later
+    // fetches hit. With a read-ahead worker this returns immediately;
+    // otherwise the pages are loaded before it returns. Pages that cannot be
+    // loaded are skipped, and their fetch reports the error.
+    pub fn prefetch(&self, pages: Range<PageId>) {
+        let pages = pages.start.0..pages.end.0;
+        match &self.read_ahead {
+            Some(read_ahead) => read_ahead.request(pages),
+            None => self.load_pages(pages),
+        }
+    }
+
+    fn load_pages(&self, pages: Range<u64>) {
+        let end = pages.end.min(self.disk_manager.get_page_count());
+        let page_ids: Vec<_> = (pages.start.max(1)..end).map(PageId).collect();
+        let page_size = self.disk_manager.get_page_size() as usize;
+        for chunk in page_ids.chunks(PREFETCH_BATCH) {
+            let mut pool = self.pool.lock();
+            let mut loads = Vec::new();
+            let mut victims = Vec::new();
+            let mut exhausted = false;
+            for &page_id in chunk {
+                if pool.peek(page_id).is_some() || self.evicting.lock().contains_key(&page_id) {
+                    continue;
+                }
+                let (frame, page) = BufferFrame::loading(page_id, page_size);
+                match self.admit(&mut pool, Alg::Hint::default(), page_id, frame.clone()) {
+                    Ok(victim) => victims.extend(victim),
+                    Err(_) => {
+                        // Every frame is pinned; do not keep trying.
+                        frame.unpin();
+                        exhausted = true;
+                        break;
+                    }
+                }
+                loads.push((frame, page));
+            }
+            drop(pool);
+
+            // A victim that cannot be written stays in `evicting`.
+            for victim in victims {
+                let _ = self.evict(Some(victim));
+            }
+
+            let mut reads: Vec<_> = loads
+                .iter_mut()
+                .map(|(frame, page)| (frame.page_id(), &mut page[..]))
+                .collect();
+            let batch_ok = self.disk_manager.read_pages(&mut reads).is_ok();
+            for (frame, mut page) in loads {
+                // Find out which pages are unreadable and skip just those.
+                let ok = batch_ok
+                    || self
+                        .disk_manager
+                        .read_page(frame.page_id(), &mut page)
+                        .is_ok();
+                if ok {
+                    bump(&self.stats.prefetched);
+                }
+                self.finish_load(&frame, page, ok);
+                frame.unpin();
+            }
+            if exhausted {
+                return;
+            }
+        }
+    }
+
+    // Makes the log durable up to `lsn` before a page with that page LSN is
+    // written.
+    fn flush_log(&self, lsn: Lsn) -> Result<(), BufferPoolError> {
+        if let Some(wal) = &self.wal {
+            wal.flush(lsn)?;
+        }
+        Ok(())
+    }
+
+    fn write_back(&self, frame: &BufferFrame) -> Result<(), BufferPoolError> {
+        let _writing = frame.inner.write_back.lock();
+        self.write_latched(frame, &frame.get_page_ref())
+    }
+
+    fn write_latched(&self, frame: &BufferFrame, page: &Page) -> Result<(), BufferPoolError> {
+        if frame.is_dirty() {
+            self.flush_log(page::page_lsn(page))?;
+            self.disk_manager.write_page(frame.page_id(), page)?;
+            frame.set_dirty(false);
+            bump(&self.stats.write_backs);
+        }
+        Ok(())
+    }
+
+    // Writes back a frame that `admit` displaced and stops tracking it. If the
+    // write fails the frame stays in `evicting`, where a fetch can take it back
+    // and `flush_all` retries it.
+    fn evict(&self, victim: Option<BufferFrame>) -> Result<(), BufferPoolError> {
+        let Some(victim) = victim else {
+            return Ok(());
+        };
+        // Misses evict while holding the latch of the page they load, so this
+        // must not wait on the victim's latch: a fetch that took the victim back
+        // may hold it and be waiting for that very load. Nothing else latches a
+        // frame for writing while it is in `evicting`, so the wait is short.
+        loop {
+            if !self.is_evicting(&victim) {
+                // Taken back; it is written whenever it is evicted again.
+                return Ok(());
+            }
+            if let Some(_writing) = victim.inner.write_back.try_lock() {
+                if let Some(page) = victim.try_get_page_ref() {
+                    self.write_latched(&victim, &page)?;
+                    break;
+                }
+            }
+            std::thread::yield_now();
+        }
+        self.forget_evicted(&victim);
+        bump(&self.stats.evictions);
+        Ok(())
+    }
+
+    fn is_evicting(&self, frame: &BufferFrame) -> bool {
+        self.evicting
+            .lock()
+            .get(&frame.page_id())
+            .is_some_and(|evicted| Arc::ptr_eq(&evicted.inner, &frame.inner))
+    }
+
+    fn forget_evicted(&self, frame: &BufferFrame) {
+        let mut evicting = self.evicting.lock();
+        let page_id = frame.page_id();
+        // A fetch may have taken the frame back, and dirtied it, since it was
+        // evicted; only a clean copy of this very frame is done with.
+        if evicting
+            .get(&page_id)
+            .is_some_and(|evicted| Arc::ptr_eq(&evicted.inner, &frame.inner))
+            && !frame.is_dirty()
+        {
+            evicting.remove(&page_id);
+        }
+    }
+
+    // Writes out up to `max_pages` dirty, unpinned frames among the next
+    // `lookahead` eviction candidates so that evictions find clean victims.
+    // Returns the number of pages written.
+    pub fn clean_ahead(
+        &self,
+        lookahead: usize,
+        max_pages: usize,
+    ) -> Result<usize, BufferPoolError> {
+        let frames: Vec<_> = self
+            .pool
+            .lock()
+            .eviction_order(lookahead)
+            .filter(|frame| !frame.is_pinned() && frame.is_dirty())
+            .take(max_pages)
+            .cloned()
+            .collect();
+        self.write_back_all(&frames)
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        BufferPoolStats {
+            hits: load(&self.stats.hits),
+            misses: load(&self.stats.misses),
+            evictions: load(&self.stats.evictions),
+            write_backs: load(&self.stats.write_backs),
+            pool_full: load(&self.stats.pool_full),
+            prefetched: load(&self.stats.prefetched),
+            disk: self.disk_manager.stats(),
+        }
+    }
+
+    pub fn resident_pages(&self) -> Vec<ResidentPage> {
+        let pool = self.pool.lock();
+        pool.frames()
+            .map(|frame| ResidentPage {
+                page_id: frame.page_id(),
+                pin_count: frame.pin_count(),
+                usage: pool.usage(frame.page_id()).unwrap_or(0),
+                is_dirty: frame.is_dirty(),
+            })
+            .collect()
+    }
+
+    // Dirty pages with logged changes and the LSN of the oldest change that
+    // has not reached disk yet, for checkpoints.
+    pub fn dirty_page_table(&self) -> Vec<(PageId, Lsn)> {
+        let pool = self.pool.lock();
+        let evicting = self.evicting.lock();
+        pool.frames()
+            .chain(evicting.values())
+            .filter(|frame| frame.is_dirty() && frame.rec_lsn() != Lsn::INVALID)
+            .map(|frame| (frame.page_id(), frame.rec_lsn()))
+            .collect()
+    }
+
+    pub fn disk_manager(&self) -> &DiskManager<S> {
+        &self.disk_manager
+    }
+
+    // Writes the page back if it is resident and dirty, and makes it durable.
+    pub fn flush_page(&self, page_id: PageId) -> Result<(), BufferPoolError> {
+        let pool = self.pool.lock();
+        let frame = pool.peek(page_id).cloned();
+        let evicted = self.evicting.lock().get(&page_id).cloned();
+        drop(pool);
+        if let Some(frame) = frame {
+            self.write_back(&frame)?;
+        }
+        if let Some(frame) = evicted {
+            self.write_back(&frame)?;
+            self.forget_evicted(&frame);
+        }
+        self.sync()
+    }
+
+    // Writes back several frames in one batch. Frames that are latched or
+    // being written elsewhere are written one at a time afterwards, since
+    // waiting for them while holding others could deadlock with a writer.
+    // The frames need not be pinned: evicting one waits for its write-back.
+    fn write_back_all(&self, frames: &[BufferFrame]) -> Result<usize, BufferPoolError> {
+        let mut latched = Vec::new();
+        let mut busy = Vec::new();
+        for frame in frames {
+            let Some(writing) = frame.inner.write_back.try_lock() else {
+                busy.push(frame);
+                continue;
+            };
+            match frame.try_get_page_ref() {
+                Some(page) if frame.is_dirty() => latched.push((frame, writing, page)),
+                Some(_) => {}
+                None => busy.push(frame),
+            }
+        }
+
+        let lsn = latched
+            .iter()
+            .map(|(_, _, page)| page::page_lsn(page))
+            .max();
+        self.flush_log(lsn.unwrap_or(Lsn::INVALID))?;
+        let batch: Vec<_> = latched
+            .iter()
+            .map(|(frame, _, page)| (frame.page_id(), &page[..]))
+            .collect();
+        self.disk_manager.write_pages(&batch)?;
+        for (frame, _, _) in &latched {
+            frame.set_dirty(false);
+            bump(&self.stats.write_backs);
+        }
+        let mut written = latched.len();
+        drop(latched);
+
+        for frame in busy {
+            if frame.is_dirty() {
+                self.write_back(frame)?;
+                written += 1;
+            }
         }
+        Ok(written)
     }
 
-    pub fn fetch_page(&mut self, page_id: PageId) -> Result<BufferFrame, std::io::Error> {
-        if let Some(frame) = self.pool.request(page_id) {
-            return Ok(frame.clone());
+    pub fn flush_all(&self) -> Result<(), BufferPoolError> {
+        let pool = self.pool.lock();
+        let evicted: Vec<_> = self.evicting.lock().values().cloned().collect();
+        let mut frames: Vec<_> = pool
+            .frames()
+            .filter(|frame| frame.is_dirty())
+            .cloned()
+            .collect();
+        drop(pool);
+        frames.extend(evicted.iter().cloned());
+        self.write_back_all(&frames)?;
+        for frame in &evicted {
+            self.forget_evicted(frame);
         }
+        self.sync()
+    }
+
+    pub fn sync(&self) -> Result<(), BufferPoolError> {
+        self.disk_manager.sync().map_err(DiskManagerError::from)?;
+        Ok(())
+    }
+
+    // Flushes every dirty frame before shutting down. Dropping the pool does
+    // the same but has to swallow errors.
+    pub fn close(mut self) -> Result<(), BufferPoolError> {
+        self.closed = true;
+        self.flush_all()
+    }
+
+    // Starts tracking `frame` and returns the frame it displaced, if any,
+    // which the caller passes to `evict` once the pool latch is released. The
+    // frame is only handed out by the caller if this succeeds.
+    fn admit(
+        &self,
+        pool: &mut Alg,
+        hint: Alg::Hint,
+        page_id: PageId,
+        frame: BufferFrame,
+    ) -> Result<Option<BufferFrame>, BufferPoolError> {
+        let outcome = pool
+            .push_with_hint(hint, page_id, frame)
+            .map_err(|e| BufferPoolError::Policy(Box::new(e)))?;
+
+        match outcome {
+            PushOutcome::Inserted => Ok(None),
+            PushOutcome::Evicted {
+                page_id: old_page_id,
+                frame: old_frame,
+            } => {
+                self.evicting.lock().insert(old_page_id, old_frame.clone());
+                Ok(Some(old_frame))
+            }
+            PushOutcome::Full => {
+                bump(&self.stats.pool_full);
+                Err(BufferPoolError::PoolExhausted)
+            }
+        }
+    }
 
-        let mut page_data = vec![0; self.disk_manager.get_page_size() as usize];
-        self.disk_manager.read_page(page_id, &mut page_data)?;
+    // Takes back a page that is on its way out, pinned and admitted again.
+    // Its contents may be newer than what is on disk.
+    fn readmit(
+        &self,
+        pool: &mut Alg,
+        hint: Alg::Hint,
+        page_id: PageId,
+    ) -> Option<Result<(BufferFrame, Option<BufferFrame>), BufferPoolError>> {
+        let frame = self.evicting.lock().remove(&page_id)?;
+        frame.pin();
+        match self.admit(pool, hint, page_id, frame.clone()) {
+            Ok(victim) => Some(Ok((frame, victim))),
+            Err(e) => {
+                frame.unpin();
+                self.evicting.lock().insert(page_id, frame);
+                Some(Err(e))
+            }
+        }
+    }
+
+    // Publishes the outcome of reading a page into a frame from `loading`.
+    // A failed frame is dropped from the pool before fetches waiting on it
+    // are let in.
+    fn finish_load(
+        &self,
+        frame: &BufferFrame,
+        page: ArcRwLockWriteGuard<RawRwLock, Page>,
+        ok: bool,
+    ) {
+        if !ok {
+            frame.inner.load_failed.store(true, Ordering::Release);
+            self.pool.lock().remove(frame.page_id());
+        }
+        frame.inner.loading.store(false, Ordering::Release);
+        drop(page);
+    }
 
-        let frame = BufferFrame::new(page_id, page_data);
-        if let Ok((old_page_id, old_frame)) = self.pool.push(page_id, frame.clone()) {
-            // Todo: Pool がいっぱいになった場合のハンドルをする。
+    pub fn fetch_page(&self, page_id: PageId) -> Result<PageGuard, BufferPoolError> {
+        self.fetch_page_with_hint(page_id, Alg::Hint::default())
+    }
+
+    pub fn fetch_page_with_hint(
+        &self,
+        page_id: PageId,
+        hint: Alg::Hint,
+    ) -> Result<PageGuard, BufferPoolError> {
+        if let Some(read_ahead) = &self.read_ahead {
+            read_ahead.observe(page_id);
+        }
+
+        loop {
+            let mut pool = self.pool.lock();
+            if let Some(frame) = pool.request_with_hint(hint, page_id) {
+                frame.pin();
+                drop(pool);
+                if !frame.wait_for_load() {
+                    continue;
+                }
+                bump(&self.stats.hits);
+                return Ok(PageGuard::new(frame));
+            }
+            bump(&self.stats.misses);
 
-            if old_frame.is_dirty() {
-                self.disk_manager
-                    .write_page(old_page_id, &old_frame.get_page_ref())?;
+            if let Some(readmitted) = self.readmit(&mut pool, hint, page_id) {
+                let (frame, victim) = readmitted?;
+                drop(pool);
+                let guard = PageGuard::new(frame);
+                self.evict(victim)?;
+                return Ok(guard);
             }
+
+            // Make room before reading, so that a full pool costs no I/O.
+            let (frame, mut page) =
+                BufferFrame::loading(page_id, self.disk_manager.get_page_size() as usize);
+            let victim = match self.admit(&mut pool, hint, page_id, frame.clone()) {
+                Ok(victim) => victim,
+                Err(e) => {
+                    frame.unpin();
+                    return Err(e);
+                }
+            };
+            drop(pool);
+
+            let guard = PageGuard::new(frame.clone());
+            let result = self.evict(victim).and_then(|()| {
+                self.disk_manager.read_page(page_id, &mut page)?;
+                Ok(())
+            });
+            self.finish_load(&frame, page, result.is_ok());
+            return result.map(|()| guard);
         }
+    }
+
+    pub fn fetch_page_read(&self, page_id: PageId) -> Result<ReadPageGuard, BufferPoolError> {
+        Ok(self.fetch_page(page_id)?.read())
+    }
+
+    pub fn fetch_page_write(&self, page_id: PageId) -> Result<WritePageGuard, BufferPoolError> {
+        Ok(self.fetch_page(page_id)?.write())
+    }
+
+    // Replaces the contents of the page with `image` without reading it from
+    // disk, where it may be torn, and returns it latched and dirty.
+    pub fn restore_page(
+        &self,
+        page_id: PageId,
+        image: &[u8],
+    ) -> Result<WritePageGuard, BufferPoolError> {
+        assert_eq!(
+            image.len() as u64,
+            self.disk_manager.get_page_size(),
+            "image is not a full page"
+        );
+        let hint = Alg::Hint::default();
+        let (guard, victim) = loop {
+            let mut pool = self.pool.lock();
+            if let Some(frame) = pool.request(page_id) {
+                frame.pin();
+                drop(pool);
+                if frame.wait_for_load() {
+                    break (PageGuard::new(frame), None);
+                }
+                continue;
+            }
+            if let Some(readmitted) = self.readmit(&mut pool, hint, page_id) {
+                let (frame, victim) = readmitted?;
+                break (PageGuard::new(frame), victim);
+            }
 
-        Ok(frame)
+            // Dirty from the start, so that the image survives even if the
+            // victim cannot be written.
+            let frame = BufferFrame::new(page_id, PageBuf::from(image));
+            frame.set_dirty(true);
+            frame.pin();
+            match self.admit(&mut pool, hint, page_id, frame.clone()) {
+                Ok(victim) => break (PageGuard::new(frame), victim),
+                Err(e) => {
+                    frame.unpin();
+                    return Err(e);
+                }
+            }
+        };
+        self.evict(victim)?;
+
+        let mut page = guard.write();
+        page.copy_from_slice(image);
+        Ok(page)
+    }
+
+    // Allocates a fresh page and returns it zeroed and dirty without reading
+    // it from disk.
+    pub fn new_page(&self) -> Result<PageGuard, BufferPoolError> {
+        let page_id = self.disk_manager.allocate_page()?;
+        let frame = BufferFrame::new(
+            page_id,
+            PageBuf::zeroed(self.disk_manager.get_page_size() as usize),
+        );
+        frame.set_dirty(true);
+        frame.pin();
+
+        let mut pool = self.pool.lock();
+        let victim = match self.admit(&mut pool, Alg::Hint::default(), page_id, frame.clone()) {
+            Ok(victim) => victim,
+            Err(e) => {
+                frame.unpin();
+                self.disk_manager.deallocate_page(page_id)?;
+                return Err(e);
+            }
+        };
+        drop(pool);
+
+        if let Err(e) = self.evict(victim) {
+            self.pool.lock().remove(page_id);
+            frame.unpin();
+            self.disk_manager.deallocate_page(page_id)?;
+            return Err(e);
+        }
+        Ok(PageGuard::new(frame))
+    }
+
+    // Drops the page from the pool without writing it back and returns its id
+    // to the allocator.
+    pub fn delete_page(&self, page_id: PageId) -> Result<(), BufferPoolError> {
+        let mut pool = self.pool.lock();
+        let frame = match pool.peek(page_id) {
+            Some(frame) if frame.is_pinned() => {
+                return Err(BufferPoolError::PagePinned(page_id));
+            }
+            Some(_) => pool.remove(page_id),
+            None => self.evicting.lock().remove(&page_id),
+        };
+        drop(pool);
+        let Some(frame) = frame else {
+            return Ok(self.disk_manager.deallocate_page(page_id)?);
+        };
+
+        // A concurrent flush or eviction may still hold this frame; make sure
+        // it does not write the page after it has been freed.
+        let writing = frame.inner.write_back.lock();
+        let rec_lsn = frame.rec_lsn();
+        frame.set_dirty(false);
+        if let Err(e) = self.disk_manager.deallocate_page(page_id) {
+            // The page may already be overwritten on disk, so the cached copy
+            // is kept, dirty, where fetches and `flush_all` find it.
+            frame.set_dirty(true);
+            frame.record_change(rec_lsn);
+            drop(writing);
+            self.evicting.lock().insert(page_id, frame);
+            return Err(e.into());
+        }
+        Ok(())
     }
 }
 
+impl<Alg: PoolAlgorithm, S: PageStore> Drop for BufferPoolManager<Alg, S> {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.flush_all();
+        }
+    }
+}
+
+// Helpers shared by the replacement policy tests.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::{BufferFrame, PushOutcome};
+    use crate::disk::PageId;
+    use crate::page::PageBuf;
+
+    pub(crate) fn create_mock_frame(id: u64) -> BufferFrame {
+        BufferFrame::new(PageId(id), PageBuf::zeroed(16))
+    }
+
+    pub(crate) fn evicted(outcome: PushOutcome) -> Option<PageId> {
+        match outcome {
+            PushOutcome::Evicted { page_id, .. } => Some(page_id),
+            _ => None,
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use super::test_util::create_mock_frame;
     use super::*;
-    use crate::disk::DiskManager;
-    use crate::disk::PageId;
     use crate::buffer::ClockSweep;
+    use crate::disk::PageId;
+    use crate::store::{FaultyStore, IoEvent, MemoryStore};
+    use crate::wal::Wal;
 
     #[test]
     fn test_clock_sweep() {
-        fn create_mock_frame(id: usize) -> BufferFrame {
-            let page_id = PageId(id as u64);
-            let page = vec![0; 4096];
-            BufferFrame::new(page_id, page)
-        }
-
         let mut pool = ClockSweep::new(Some(3));
 
         let frame1 = create_mock_frame(1);
@@ -233,17 +1264,25 @@ mod tests {
         let frame4 = create_mock_frame(4);
         let frame5 = create_mock_frame(5);
 
-        assert_eq!(pool.push(PageId(1), frame1.clone()), Err(ClockSweepError::Success));
-        assert_eq!(pool.push(PageId(2), frame2), Err(ClockSweepError::Success));
-        assert_eq!(pool.push(PageId(3), frame3), Err(ClockSweepError::Success));
+        assert_eq!(pool.push(PageId(1), frame1), Ok(PushOutcome::Inserted));
+        assert_eq!(pool.push(PageId(2), frame2), Ok(PushOutcome::Inserted));
+        assert_eq!(pool.push(PageId(3), frame3), Ok(PushOutcome::Inserted));
 
         assert_eq!(pool.frames.len(), 3);
         assert_eq!(pool.map.len(), 3);
 
         let got_frame2 = pool.request(PageId(2)).unwrap();
         let got_frame3 = pool.request(PageId(3)).unwrap();
+        got_frame2.pin();
+        got_frame3.pin();
 
-        assert_eq!(pool.push(PageId(4), frame4.clone()), Ok((PageId(1), frame1.clone())));
+        assert_eq!(
+            pool.push(PageId(4), frame4),
+            Ok(PushOutcome::Evicted {
+                page_id: PageId(1),
+                frame: create_mock_frame(1)
+            })
+        );
 
         assert_eq!(pool.frames.len(), 3);
         assert_eq!(pool.map.len(), 3);
@@ -257,11 +1296,17 @@ mod tests {
         assert_eq!(pool.frames[2].0, 1);
         assert_eq!(pool.frames[2].1.page_id(), PageId(3));
 
-        drop(got_frame2);
-        drop(got_frame3);
+        got_frame2.unpin();
+        got_frame3.unpin();
 
         pool.clock_hand = 1;
-        assert_eq!(pool.push(PageId(5), frame5.clone()), Ok((PageId(4), frame4.clone())));
+        assert_eq!(
+            pool.push(PageId(5), frame5),
+            Ok(PushOutcome::Evicted {
+                page_id: PageId(4),
+                frame: create_mock_frame(4)
+            })
+        );
 
         assert_eq!(pool.frames.len(), 3);
         assert_eq!(pool.map.len(), 3);
@@ -274,5 +1319,576 @@ mod tests {
 
         assert_eq!(pool.frames[2].0, 0);
         assert_eq!(pool.frames[2].1.page_id(), PageId(3));
+
+        assert_eq!(
+            pool.push(PageId(2), create_mock_frame(2)),
+            Err(ClockSweepError::AlreadyResident(PageId(2)))
+        );
+
+        for id in [5, 2, 3] {
+            pool.request(PageId(id)).unwrap().pin();
+        }
+        assert_eq!(
+            pool.push(PageId(6), create_mock_frame(6)),
+            Ok(PushOutcome::Full)
+        );
+    }
+
+    fn check_policy<Alg: PoolAlgorithm>() {
+        let mut pool = Alg::new(Some(3));
+        for id in 1..=3 {
+            assert_eq!(
+                pool.push(PageId(id), create_mock_frame(id)).unwrap(),
+                PushOutcome::Inserted
+            );
+        }
+        assert!(pool.push(PageId(2), create_mock_frame(2)).is_err());
+        assert_eq!(pool.frames().count(), 3);
+
+        pool.request(PageId(1)).unwrap().pin();
+        pool.request(PageId(2)).unwrap().pin();
+        assert!(pool.request(PageId(9)).is_none());
+        assert!(matches!(
+            pool.push(PageId(4), create_mock_frame(4)).unwrap(),
+            PushOutcome::Evicted { page_id, frame }
+                if page_id == PageId(3) && frame.page_id() == page_id
+        ));
+        assert!(pool.peek(PageId(3)).is_none());
+
+        pool.request(PageId(4)).unwrap().pin();
+        assert_eq!(
+            pool.push(PageId(5), create_mock_frame(5)).unwrap(),
+            PushOutcome::Full
+        );
+        assert!(pool.peek(PageId(5)).is_none());
+        assert_eq!(pool.frames().filter(|frame| frame.is_pinned()).count(), 3);
+
+        for frame in pool.frames() {
+            frame.unpin();
+        }
+        assert_eq!(pool.remove(PageId(4)).unwrap().page_id(), PageId(4));
+        assert_eq!(pool.frames().count(), 2);
+        assert_eq!(
+            pool.push(PageId(5), create_mock_frame(5)).unwrap(),
+            PushOutcome::Inserted
+        );
+        assert_eq!(pool.counters(), PolicyCounters { hits: 3, misses: 1 });
+    }
+
+    #[test]
+    fn test_policies_share_behaviour() {
+        check_policy::<ClockSweep>();
+        check_policy::<LruK>();
+        check_policy::<AdaptiveReplacement>();
+        check_policy::<TwoQueue>();
+    }
+
+    fn check_scan_keeps_hot_pages<Alg: PoolAlgorithm<Hint = AccessHint>>() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..100).map(|_| disk.allocate_page().unwrap()).collect();
+        let (hot, scan) = pages.split_at(8);
+        let bpm = BufferPoolManager::<Alg, _>::new(disk, 16);
+
+        for _ in 0..2 {
+            for &page_id in hot {
+                bpm.fetch_page_with_hint(page_id, AccessHint::KeepHot)
+                    .unwrap();
+            }
+        }
+        for &page_id in scan {
+            bpm.fetch_page_with_hint(page_id, AccessHint::Sequential)
+                .unwrap();
+        }
+
+        let pool = bpm.pool.lock();
+        assert!(hot.iter().all(|&page_id| pool.peek(page_id).is_some()));
+    }
+
+    #[test]
+    fn test_sequential_scans_keep_hot_pages() {
+        check_scan_keeps_hot_pages::<ClockSweep>();
+        check_scan_keeps_hot_pages::<LruK>();
+        check_scan_keeps_hot_pages::<AdaptiveReplacement>();
+        check_scan_keeps_hot_pages::<TwoQueue>();
+    }
+
+    #[test]
+    fn test_clock_sweep_recycles_ring_frames() {
+        let mut pool = ClockSweep::new(Some(16));
+        for id in 1..=14 {
+            pool.push(PageId(id), create_mock_frame(id)).unwrap();
+            pool.request(PageId(id)).unwrap();
+        }
+        for id in 15..=16 {
+            let outcome =
+                pool.push_with_hint(AccessHint::Sequential, PageId(id), create_mock_frame(id));
+            assert_eq!(outcome, Ok(PushOutcome::Inserted));
+        }
+
+        for id in 17..40 {
+            let outcome =
+                pool.push_with_hint(AccessHint::Sequential, PageId(id), create_mock_frame(id));
+            assert!(matches!(
+                outcome,
+                Ok(PushOutcome::Evicted { page_id, .. }) if page_id == PageId(id - 2)
+            ));
+        }
+        assert!((1..=14).all(|id| pool.peek(PageId(id)).is_some()));
+    }
+
+    #[test]
+    fn test_buffer_pool_stats() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let page1 = disk.allocate_page().unwrap();
+        let page2 = disk.allocate_page().unwrap();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1);
+
+        bpm.fetch_page_write(page1).unwrap()[0] = 1;
+        let guard = bpm.fetch_page(page2).unwrap();
+        bpm.fetch_page(page2).unwrap();
+        assert!(bpm.fetch_page(page1).is_err());
+
+        let stats = bpm.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 3, 1));
+        assert_eq!((stats.write_backs, stats.pool_full), (1, 1));
+        // Pages past the end of the file read back as zeroes without any bytes
+        // transferred, and the last miss finds no victim before reading.
+        assert_eq!((stats.disk.reads, stats.disk.bytes_read), (2, 0));
+        assert_eq!((stats.disk.writes, stats.disk.bytes_written), (1, 4096));
+
+        assert_eq!(
+            bpm.resident_pages(),
+            vec![ResidentPage {
+                page_id: page2,
+                pin_count: 1,
+                usage: 1,
+                is_dirty: false,
+            }]
+        );
+        drop(guard);
+    }
+
+    #[test]
+    fn test_clean_ahead_writes_unpinned_victims() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..5).map(|_| disk.allocate_page().unwrap()).collect();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 4);
+        for &page_id in &pages[..4] {
+            bpm.fetch_page_write(page_id).unwrap()[0] = 1;
+        }
+
+        let pinned = bpm.fetch_page(pages[3]).unwrap();
+        assert_eq!(bpm.clean_ahead(4, 2).unwrap(), 2);
+        assert_eq!(bpm.clean_ahead(4, 4).unwrap(), 1);
+        assert_eq!(bpm.clean_ahead(4, 4).unwrap(), 0);
+
+        let writes = bpm.stats().disk.writes;
+        bpm.fetch_page(pages[4]).unwrap();
+        assert_eq!(bpm.stats().disk.writes, writes);
+        drop(pinned);
+    }
+
+    #[test]
+    fn test_clean_ahead_does_not_pin_frames() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..2).map(|_| disk.allocate_page().unwrap()).collect();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1);
+        bpm.fetch_page_write(pages[0]).unwrap()[0] = 1;
+        let frame = bpm.pool.lock().peek(pages[0]).unwrap().clone();
+
+        // Stall the write-back; a fetch that needs the frame must wait for
+        // it rather than find the only frame pinned.
+        let writing = frame.inner.write_back.lock();
+        std::thread::scope(|scope| {
+            let cleaner = scope.spawn(|| bpm.clean_ahead(1, 1));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!frame.is_pinned());
+            let fetch = scope.spawn(|| bpm.fetch_page(pages[1]).map(drop));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            drop(writing);
+            cleaner.join().unwrap().unwrap();
+            fetch.join().unwrap().unwrap();
+        });
+        assert_eq!(bpm.fetch_page_read(pages[0]).unwrap()[0], 1);
+    }
+
+    #[test]
+    fn test_flush_all_does_not_pin_frames() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..2).map(|_| disk.allocate_page().unwrap()).collect();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1);
+        bpm.fetch_page_write(pages[0]).unwrap()[0] = 1;
+        let frame = bpm.pool.lock().peek(pages[0]).unwrap().clone();
+
+        let writing = frame.inner.write_back.lock();
+        std::thread::scope(|scope| {
+            let flush = scope.spawn(|| bpm.flush_all());
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!frame.is_pinned());
+            let fetch = scope.spawn(|| bpm.fetch_page(pages[1]).map(drop));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            drop(writing);
+            flush.join().unwrap().unwrap();
+            fetch.join().unwrap().unwrap();
+        });
+        assert!(!frame.is_dirty());
+    }
+
+    #[test]
+    fn test_prefetch_loads_unpinned_pages() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..6).map(|_| disk.allocate_page().unwrap()).collect();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 8);
+
+        bpm.prefetch(pages[0]..pages[4]);
+        let resident = bpm.resident_pages();
+        assert_eq!(resident.len(), 4);
+        assert!(resident.iter().all(|page| page.pin_count == 0));
+
+        bpm.fetch_page(pages[2]).unwrap();
+        let stats = bpm.stats();
+        assert_eq!((stats.prefetched, stats.hits, stats.misses), (4, 1, 0));
+    }
+
+    #[test]
+    fn test_sequential_fetches_trigger_read_ahead() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..20).map(|_| disk.allocate_page().unwrap()).collect();
+        let options = ReadAheadOptions {
+            window: 8,
+            trigger: 2,
+        };
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 32).with_read_ahead(options);
+
+        for &page_id in &pages[..2] {
+            bpm.fetch_page(page_id).unwrap();
+        }
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while bpm.pool.lock().peek(pages[9]).is_none() {
+            assert!(std::time::Instant::now() < deadline, "read-ahead never ran");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        bpm.fetch_page(pages[2]).unwrap();
+        assert_eq!(bpm.stats().misses, 2);
+        assert_eq!(bpm.stats().prefetched, 8);
+    }
+
+    #[test]
+    fn test_buffer_pool_writes_back_evicted_pages() {
+        let store = FaultyStore::new(MemoryStore::new());
+        let faults = store.handle();
+        let disk = DiskManager::from_store(store, 4096).unwrap();
+        let page1 = disk.allocate_page().unwrap();
+        let page2 = disk.allocate_page().unwrap();
+
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1);
+        bpm.fetch_page_write(page1).unwrap()[0] = 42;
+        bpm.fetch_page(page2).unwrap();
+        assert_eq!(bpm.fetch_page_read(page1).unwrap()[0], 42);
+
+        faults.fail_reads(true);
+        assert!(matches!(
+            bpm.fetch_page(page2),
+            Err(BufferPoolError::Disk(DiskManagerError::Io(_)))
+        ));
+    }
+
+    #[test]
+    fn test_flushed_pages_survive_power_loss() {
+        let inner = MemoryStore::new();
+        let store = FaultyStore::new(inner.clone());
+        let faults = store.handle();
+        let disk = DiskManager::from_store(store, 4096).unwrap();
+        let page1 = disk.allocate_page().unwrap();
+        let page2 = disk.allocate_page().unwrap();
+        disk.sync().unwrap();
+
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 4);
+        bpm.fetch_page_write(page1).unwrap()[0] = 1;
+        bpm.fetch_page_write(page2).unwrap()[0] = 2;
+        bpm.flush_page(page1).unwrap();
+        assert!(!bpm.fetch_page(page1).unwrap().is_dirty());
+        assert!(bpm.fetch_page(page2).unwrap().is_dirty());
+
+        faults.power_loss();
+        drop(bpm);
+
+        let disk = DiskManager::open_store(inner).unwrap();
+        let mut page = vec![0; 4096];
+        disk.read_page(page1, &mut page).unwrap();
+        assert_eq!(page[0], 1);
+        disk.read_page(page2, &mut page).unwrap();
+        assert_eq!(page[0], 0);
+    }
+
+    #[test]
+    fn test_dirty_pages_wait_for_the_log() {
+        let log = FaultyStore::new(MemoryStore::new());
+        let log_faults = log.handle();
+        let wal = Arc::new(Wal::from_store(log).unwrap());
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let page1 = disk.allocate_page().unwrap();
+        let page2 = disk.allocate_page().unwrap();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1).with_wal(wal.clone());
+
+        let lsn = wal.append(b"update");
+        let mut page = bpm.fetch_page_write(page1).unwrap();
+        page[page::PAGE_HEADER_SIZE] = 1;
+        page.set_page_lsn(lsn);
+        drop(page);
+
+        log_faults.fail_syncs(true);
+        assert!(matches!(
+            bpm.fetch_page(page2),
+            Err(BufferPoolError::Wal(WalError::Io(_)))
+        ));
+        assert_eq!(bpm.stats().disk.writes, 0);
+
+        log_faults.fail_syncs(false);
+        bpm.flush_all().unwrap();
+        assert!(wal.is_durable(lsn));
+        assert_eq!(bpm.stats().disk.writes, 1);
+        bpm.fetch_page(page2).unwrap();
+        assert_eq!(bpm.fetch_page_write(page1).unwrap().page_lsn(), lsn);
+    }
+
+    #[test]
+    fn test_close_flushes_dirty_pages() {
+        let store = MemoryStore::new();
+        let disk = DiskManager::from_store(store.clone(), 4096).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 4);
+        bpm.fetch_page_write(page_id).unwrap()[0] = 7;
+        bpm.close().unwrap();
+
+        let disk = DiskManager::open_store(store).unwrap();
+        let mut page = vec![0; 4096];
+        disk.read_page(page_id, &mut page).unwrap();
+        assert_eq!(page[0], 7);
+    }
+
+    #[test]
+    fn test_new_and_delete_page() {
+        let store = FaultyStore::new(MemoryStore::new());
+        let faults = store.handle();
+        let disk = DiskManager::from_store(store, 4096).unwrap();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 4);
+
+        faults.clear_trace();
+        let frame = bpm.new_page().unwrap().read();
+        assert!(frame.iter().all(|&b| b == 0));
+        assert!(!faults
+            .trace()
+            .iter()
+            .any(|event| matches!(event, IoEvent::Read { .. })));
+
+        let page_id = frame.page_id();
+        assert!(matches!(
+            bpm.delete_page(page_id),
+            Err(BufferPoolError::PagePinned(id)) if id == page_id
+        ));
+        drop(frame);
+        bpm.delete_page(page_id).unwrap();
+
+        let mut frame = bpm.new_page().unwrap().write();
+        assert_eq!(frame.page_id(), page_id);
+        frame[100] = 7;
+        drop(frame);
+
+        // Freeing overwrites the page and then fails to update the superblock;
+        // the cached copy is all that is left of the page.
+        faults.fail_nth_write(2);
+        assert!(bpm.delete_page(page_id).is_err());
+        assert_eq!(bpm.fetch_page_read(page_id).unwrap()[100], 7);
+        bpm.flush_all().unwrap();
+        assert_ne!(bpm.new_page().unwrap().page_id(), page_id);
+        bpm.delete_page(page_id).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_page_reports_exhausted_pool() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let page1 = disk.allocate_page().unwrap();
+        let page2 = disk.allocate_page().unwrap();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1);
+
+        let pinned = bpm.fetch_page(page1).unwrap();
+        assert!(matches!(
+            bpm.fetch_page(page2),
+            Err(BufferPoolError::PoolExhausted)
+        ));
+        assert!(matches!(
+            bpm.new_page(),
+            Err(BufferPoolError::PoolExhausted)
+        ));
+
+        let mut pinned = pinned.write();
+        pinned[0] = 1;
+        drop(pinned);
+        assert_eq!(bpm.fetch_page_read(page1).unwrap()[0], 1);
+        bpm.fetch_page(page2).unwrap();
+        assert_eq!(bpm.new_page().unwrap().page_id(), PageId(3));
+    }
+
+    #[test]
+    fn test_failed_write_back_keeps_dirty_victim() {
+        let store = FaultyStore::new(MemoryStore::new());
+        let faults = store.handle();
+        let disk = DiskManager::from_store(store, 4096).unwrap();
+        let page1 = disk.allocate_page().unwrap();
+        let page2 = disk.allocate_page().unwrap();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1);
+
+        bpm.fetch_page_write(page1).unwrap()[0] = 1;
+        faults.fail_nth_write(1);
+        assert!(bpm.fetch_page(page2).is_err());
+
+        let frame = bpm.fetch_page_read(page1).unwrap();
+        assert!(frame.guard.is_dirty());
+        assert_eq!(frame[0], 1);
+    }
+
+    #[test]
+    fn test_hits_do_not_wait_for_eviction_io() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 2);
+        bpm.fetch_page_write(pages[0]).unwrap()[0] = 1;
+        let pinned = bpm.fetch_page(pages[1]).unwrap();
+        let victim = bpm.pool.lock().peek(pages[0]).unwrap().clone();
+
+        // Stall the write-back of the victim while the miss that evicts it
+        // is in flight.
+        let writing = victim.inner.write_back.lock();
+        std::thread::scope(|scope| {
+            let miss = scope.spawn(|| bpm.fetch_page_read(pages[2]).map(|page| page[0]));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(bpm.pool.try_lock().is_some());
+            assert_eq!(bpm.fetch_page(pages[1]).unwrap().page_id(), pages[1]);
+            drop(writing);
+            assert_eq!(miss.join().unwrap().unwrap(), 0);
+        });
+        drop(pinned);
+        assert_eq!(bpm.stats().evictions, 1);
+        assert_eq!(bpm.fetch_page_read(pages[0]).unwrap()[0], 1);
+    }
+
+    #[test]
+    fn test_miss_does_not_wait_for_a_victim_taken_back() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..4).map(|_| disk.allocate_page().unwrap()).collect();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 3);
+        bpm.fetch_page_write(pages[0]).unwrap()[0] = 1;
+        for _ in 0..3 {
+            bpm.fetch_page(pages[1]).unwrap();
+        }
+        let pinned = bpm.fetch_page(pages[2]).unwrap();
+        let victim = bpm.pool.lock().peek(pages[0]).unwrap().clone();
+
+        // The miss on pages[3] evicts pages[0] and stalls writing it back. A
+        // second thread takes the victim back, latches it and then waits for
+        // the page the miss is still loading.
+        let writing = victim.inner.write_back.lock();
+        std::thread::scope(|scope| {
+            let miss = scope.spawn(|| bpm.fetch_page_read(pages[3]).map(|page| page[0]));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            let readmit = scope.spawn(|| {
+                let mut page = bpm.fetch_page_write(pages[0]).unwrap();
+                page[0] += 1;
+                bpm.fetch_page_read(pages[3]).map(|page| page[0])
+            });
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            drop(writing);
+            assert_eq!(miss.join().unwrap().unwrap(), 0);
+            assert_eq!(readmit.join().unwrap().unwrap(), 0);
+        });
+        drop(pinned);
+        assert_eq!(bpm.fetch_page_read(pages[0]).unwrap()[0], 2);
+    }
+
+    #[test]
+    fn test_miss_takes_back_a_page_being_evicted() {
+        let store = FaultyStore::new(MemoryStore::new());
+        let faults = store.handle();
+        let disk = DiskManager::from_store(store, 4096).unwrap();
+        let page1 = disk.allocate_page().unwrap();
+        let page2 = disk.allocate_page().unwrap();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1);
+
+        bpm.fetch_page_write(page1).unwrap()[0] = 1;
+        faults.fail_writes(true);
+        assert!(bpm.fetch_page(page2).is_err());
+        assert!(bpm.flush_all().is_err());
+
+        // The victim was never written, so its only copy is in memory.
+        faults.clear_trace();
+        assert_eq!(bpm.fetch_page_read(page1).unwrap()[0], 1);
+        assert!(faults.trace().is_empty());
+
+        faults.fail_writes(false);
+        bpm.flush_all().unwrap();
+        assert!(bpm.evicting.lock().is_empty());
+    }
+
+    #[test]
+    fn test_buffer_pool_is_shared_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<BufferPoolManager<ClockSweep, MemoryStore>>();
+
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..8).map(|_| disk.allocate_page().unwrap()).collect();
+        let bpm = Arc::new(BufferPoolManager::<ClockSweep, _>::new(disk, 4));
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let bpm = Arc::clone(&bpm);
+                let pages = pages.clone();
+                std::thread::spawn(move || {
+                    for i in 0..200 {
+                        let mut page = bpm.fetch_page_write(pages[i % pages.len()]).unwrap();
+                        let count = u64::from_le_bytes(page[..8].try_into().unwrap());
+                        page[..8].copy_from_slice(&(count + 1).to_le_bytes());
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        for &page_id in &pages {
+            let page = bpm.fetch_page_read(page_id).unwrap();
+            assert_eq!(u64::from_le_bytes(page[..8].try_into().unwrap()), 100);
+        }
+    }
+
+    #[test]
+    fn test_page_guards_pin_and_track_dirtiness() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let page1 = disk.allocate_page().unwrap();
+        let page2 = disk.allocate_page().unwrap();
+        let bpm = BufferPoolManager::<ClockSweep, _>::new(disk, 1);
+
+        let guard = bpm.fetch_page_write(page1).unwrap();
+        assert_eq!(guard[0], 0);
+        assert!(!guard.is_dirty());
+        drop(guard);
+
+        let read1 = bpm.fetch_page_read(page1).unwrap();
+        let read2 = bpm.fetch_page_read(page1).unwrap();
+        assert_eq!(bpm.pool.lock().peek(page1).unwrap().pin_count(), 2);
+        assert!(matches!(
+            bpm.fetch_page(page2),
+            Err(BufferPoolError::PoolExhausted)
+        ));
+        drop(read1);
+        drop(read2);
+        assert_eq!(bpm.pool.lock().peek(page1).unwrap().pin_count(), 0);
+
+        let mut guard = bpm.fetch_page_write(page2).unwrap();
+        guard[0] = 1;
+        assert!(guard.is_dirty());
     }
 }
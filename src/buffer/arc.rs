@@ -0,0 +1,279 @@
+use super::{lru_unpinned, AccessHint, BufferFrame, PolicyCounters, PoolAlgorithm, PushOutcome};
+use crate::disk::PageId;
+
+use lru::LruCache;
+use thiserror::Error;
+
+// ARC (Megiddo & Modha). Resident pages live in `recent` (seen once) or
+// `frequent` (seen again while resident or shortly after eviction). Evicted
+// pages leave their id in the matching ghost list; a hit in a ghost list moves
+// `target`, the share of the pool given to `recent`, towards that list.
+#[derive(Debug)]
+pub struct AdaptiveReplacement {
+    capacity: usize,
+    target: usize,
+    recent: LruCache<PageId, BufferFrame>,
+    frequent: LruCache<PageId, BufferFrame>,
+    recent_ghosts: LruCache<PageId, ()>,
+    frequent_ghosts: LruCache<PageId, ()>,
+    counters: PolicyCounters,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AdaptiveReplacementError {
+    #[error("page {0:?} is already resident")]
+    AlreadyResident(PageId),
+}
+
+impl AdaptiveReplacement {
+    fn len(&self) -> usize {
+        self.recent.len() + self.frequent.len()
+    }
+
+    fn adapted_target(&self, page_id: PageId) -> usize {
+        let recent = self.recent_ghosts.len().max(1);
+        let frequent = self.frequent_ghosts.len().max(1);
+        if self.recent_ghosts.contains(&page_id) {
+            (self.target + (frequent / recent).max(1)).min(self.capacity)
+        } else if self.frequent_ghosts.contains(&page_id) {
+            self.target.saturating_sub((recent / frequent).max(1))
+        } else {
+            self.target
+        }
+    }
+
+    // Returns the victim and whether it came from `recent`. Falls back to the
+    // other list when every frame in the preferred one is pinned.
+    fn select_victim(&self, target: usize, page_id: PageId) -> Option<(PageId, bool)> {
+        let prefer_recent = !self.recent.is_empty()
+            && (self.recent.len() > target
+                || (self.frequent_ghosts.contains(&page_id) && self.recent.len() == target));
+
+        let from_recent = lru_unpinned(&self.recent).map(|page_id| (page_id, true));
+        let from_frequent = lru_unpinned(&self.frequent).map(|page_id| (page_id, false));
+        if prefer_recent {
+            from_recent.or(from_frequent)
+        } else {
+            from_frequent.or(from_recent)
+        }
+    }
+
+    fn trim_ghosts(&mut self) {
+        while self.recent.len() + self.recent_ghosts.len() > self.capacity
+            && self.recent_ghosts.pop_lru().is_some()
+        {}
+        while self.len() + self.recent_ghosts.len() + self.frequent_ghosts.len() > 2 * self.capacity
+            && self.frequent_ghosts.pop_lru().is_some()
+        {}
+    }
+}
+
+impl PoolAlgorithm for AdaptiveReplacement {
+    type Hint = AccessHint;
+    type PushError = AdaptiveReplacementError;
+
+    fn new(size_hint: Option<usize>) -> Self {
+        Self {
+            capacity: size_hint.unwrap_or(1024),
+            target: 0,
+            recent: LruCache::unbounded(),
+            frequent: LruCache::unbounded(),
+            recent_ghosts: LruCache::unbounded(),
+            frequent_ghosts: LruCache::unbounded(),
+            counters: PolicyCounters::default(),
+        }
+    }
+
+    fn request_with_hint(&mut self, hint: Self::Hint, page_id: PageId) -> Option<BufferFrame> {
+        match hint {
+            AccessHint::Random | AccessHint::KeepHot => self.request(page_id),
+            // Scans and one-off accesses must not promote pages to `frequent`.
+            AccessHint::Sequential | AccessHint::OneShot => {
+                let frame = self.peek(page_id).cloned();
+                self.counters.record(frame.is_some());
+                frame
+            }
+        }
+    }
+
+    fn request(&mut self, page_id: PageId) -> Option<BufferFrame> {
+        if let Some(frame) = self.recent.pop(&page_id) {
+            self.frequent.put(page_id, frame);
+        }
+        let frame = self.frequent.get(&page_id).cloned();
+        self.counters.record(frame.is_some());
+        frame
+    }
+
+    fn peek(&self, page_id: PageId) -> Option<&BufferFrame> {
+        self.recent
+            .peek(&page_id)
+            .or_else(|| self.frequent.peek(&page_id))
+    }
+
+    fn frames(&self) -> impl Iterator<Item = &BufferFrame> {
+        self.recent
+            .iter()
+            .chain(self.frequent.iter())
+            .map(|(_, frame)| frame)
+    }
+
+    fn eviction_order(&self, limit: usize) -> impl Iterator<Item = &BufferFrame> {
+        let (first, second) = if self.recent.len() > self.target {
+            (&self.recent, &self.frequent)
+        } else {
+            (&self.frequent, &self.recent)
+        };
+        first
+            .iter()
+            .rev()
+            .chain(second.iter().rev())
+            .take(limit)
+            .map(|(_, frame)| frame)
+    }
+
+    fn remove(&mut self, page_id: PageId) -> Option<BufferFrame> {
+        self.recent
+            .pop(&page_id)
+            .or_else(|| self.frequent.pop(&page_id))
+    }
+
+    fn push(
+        &mut self,
+        page_id: PageId,
+        frame: BufferFrame,
+    ) -> Result<PushOutcome, Self::PushError> {
+        if self.peek(page_id).is_some() {
+            return Err(AdaptiveReplacementError::AlreadyResident(page_id));
+        }
+
+        let target = self.adapted_target(page_id);
+        let mut outcome = PushOutcome::Inserted;
+        if self.len() >= self.capacity {
+            let Some((victim, from_recent)) = self.select_victim(target, page_id) else {
+                return Ok(PushOutcome::Full);
+            };
+            let old_frame = if from_recent {
+                self.recent_ghosts.put(victim, ());
+                self.recent.pop(&victim)
+            } else {
+                self.frequent_ghosts.put(victim, ());
+                self.frequent.pop(&victim)
+            };
+            outcome = PushOutcome::Evicted {
+                page_id: victim,
+                frame: old_frame.unwrap(),
+            };
+        }
+
+        self.target = target;
+        let ghost = self.recent_ghosts.pop(&page_id).is_some()
+            | self.frequent_ghosts.pop(&page_id).is_some();
+        if ghost {
+            self.frequent.put(page_id, frame);
+        } else {
+            self.recent.put(page_id, frame);
+        }
+        self.trim_ghosts();
+
+        Ok(outcome)
+    }
+
+    fn push_with_hint(
+        &mut self,
+        hint: Self::Hint,
+        page_id: PageId,
+        frame: BufferFrame,
+    ) -> Result<PushOutcome, Self::PushError> {
+        if matches!(hint, AccessHint::Sequential | AccessHint::OneShot)
+            && self.peek(page_id).is_none()
+        {
+            // Going back over a page is not a sign of reuse, so a ghost hit
+            // neither promotes it nor moves `target`.
+            self.recent_ghosts.pop(&page_id);
+            self.frequent_ghosts.pop(&page_id);
+        }
+        let outcome = self.push(page_id, frame)?;
+        match hint {
+            AccessHint::KeepHot => {
+                if let Some(frame) = self.recent.pop(&page_id) {
+                    self.frequent.put(page_id, frame);
+                }
+            }
+            // The page is the first to go, ahead of pages seen only once.
+            AccessHint::Sequential | AccessHint::OneShot => self.recent.demote(&page_id),
+            AccessHint::Random => {}
+        }
+        Ok(outcome)
+    }
+
+    fn counters(&self) -> PolicyCounters {
+        self.counters
+    }
+
+    fn usage(&self, page_id: PageId) -> Option<u64> {
+        if self.frequent.contains(&page_id) {
+            Some(1)
+        } else {
+            self.recent.contains(&page_id).then_some(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::test_util::{create_mock_frame, evicted};
+
+    #[test]
+    fn test_arc_keeps_frequent_pages_during_scan() {
+        let mut pool = AdaptiveReplacement::new(Some(3));
+        pool.push(PageId(1), create_mock_frame(1)).unwrap();
+        pool.request(PageId(1)).unwrap();
+
+        for id in 2..10 {
+            let outcome = pool.push(PageId(id), create_mock_frame(id)).unwrap();
+            if id > 3 {
+                assert_eq!(evicted(outcome), Some(PageId(id - 2)));
+            }
+        }
+        assert!(pool.frequent.contains(&PageId(1)));
+    }
+
+    #[test]
+    fn test_arc_scans_evict_their_own_pages() {
+        let mut pool = AdaptiveReplacement::new(Some(4));
+        for id in 1..=3 {
+            pool.push(PageId(id), create_mock_frame(id)).unwrap();
+        }
+
+        // The second pass finds the scanned pages in the ghost list.
+        for _ in 0..2 {
+            for id in 10..20 {
+                let outcome = pool
+                    .push_with_hint(AccessHint::Sequential, PageId(id), create_mock_frame(id))
+                    .unwrap();
+                assert!(evicted(outcome).is_none_or(|page_id| page_id.0 >= 10));
+            }
+        }
+        assert!((1..=3).all(|id| pool.peek(PageId(id)).is_some()));
+        assert_eq!(pool.target, 0);
+    }
+
+    #[test]
+    fn test_arc_ghost_hit_grows_recent_target() {
+        let mut pool = AdaptiveReplacement::new(Some(3));
+        for id in 1..=3 {
+            pool.push(PageId(id), create_mock_frame(id)).unwrap();
+        }
+        pool.request(PageId(2)).unwrap();
+        pool.push(PageId(4), create_mock_frame(4)).unwrap();
+        assert!(pool.recent_ghosts.contains(&PageId(1)));
+
+        let outcome = pool.push(PageId(1), create_mock_frame(1)).unwrap();
+        assert_eq!(evicted(outcome), Some(PageId(3)));
+        assert_eq!(pool.target, 1);
+        assert!(pool.frequent.contains(&PageId(1)));
+        assert!(!pool.recent_ghosts.contains(&PageId(1)));
+    }
+}
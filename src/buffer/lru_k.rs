@@ -0,0 +1,277 @@
+use super::{AccessHint, BufferFrame, PolicyCounters, PoolAlgorithm, PushOutcome};
+use crate::disk::PageId;
+
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+use thiserror::Error;
+
+#[derive(Debug)]
+struct Entry {
+    frame: BufferFrame,
+    // Times of the last `k` uncorrelated references, most recent first.
+    history: VecDeque<u64>,
+    last: u64,
+}
+
+// LRU-K (O'Neil et al.). Evicts the unpinned page whose K-th most recent
+// uncorrelated reference is oldest; pages referenced fewer than K times go
+// first. References within `correlated_period` ticks of the previous one count
+// as a single reference. The history of evicted pages is retained, so a page
+// that comes back quickly is not treated as cold.
+#[derive(Debug)]
+pub struct LruK {
+    k: usize,
+    correlated_period: u64,
+    capacity: usize,
+    clock: u64,
+    frames: HashMap<PageId, Entry>,
+    retained: LruCache<PageId, (VecDeque<u64>, u64)>,
+    counters: PolicyCounters,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LruKError {
+    #[error("page {0:?} is already resident")]
+    AlreadyResident(PageId),
+}
+
+impl LruK {
+    pub fn with_params(size: usize, k: usize, correlated_period: u64) -> Self {
+        assert!(k > 0, "LRU-K needs k >= 1");
+        Self {
+            k,
+            correlated_period,
+            capacity: size,
+            clock: 0,
+            frames: HashMap::with_capacity(size),
+            retained: LruCache::new(NonZeroUsize::new(size.max(1)).unwrap()),
+            counters: PolicyCounters::default(),
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn reference(&self, history: &mut VecDeque<u64>, last: &mut u64, now: u64) {
+        if now - *last > self.correlated_period || history.is_empty() {
+            // Close the correlated period: shift the older references by its
+            // length so that it counts as a single reference.
+            let correlated = history.front().map_or(0, |&first| *last - first);
+            for time in history.iter_mut() {
+                *time += correlated;
+            }
+            history.push_front(now);
+            history.truncate(self.k);
+        }
+        *last = now;
+    }
+
+    // Fills the history up to K references so the page has the shortest
+    // possible K-distance.
+    fn make_hot(&mut self, page_id: PageId) {
+        let k = self.k;
+        if let Some(entry) = self.frames.get_mut(&page_id) {
+            while entry.history.len() < k {
+                entry.history.push_back(entry.last);
+            }
+        }
+    }
+
+    // Backward K-distance key: smaller means a better victim.
+    fn victim_key(&self, entry: &Entry) -> (u64, u64) {
+        let kth = entry.history.get(self.k - 1).copied().unwrap_or(0);
+        (kth, entry.history.back().copied().unwrap_or(0))
+    }
+
+    fn select_victim(&self, now: u64) -> Option<PageId> {
+        let unpinned = || self.frames.iter().filter(|(_, e)| !e.frame.is_pinned());
+        let eligible = unpinned()
+            .filter(|(_, e)| now - e.last > self.correlated_period)
+            .min_by_key(|(_, e)| self.victim_key(e));
+
+        // Only fall back to pages inside their correlated period when
+        // nothing else can go.
+        eligible
+            .or_else(|| unpinned().min_by_key(|(_, e)| self.victim_key(e)))
+            .map(|(&page_id, _)| page_id)
+    }
+}
+
+impl PoolAlgorithm for LruK {
+    type Hint = AccessHint;
+    type PushError = LruKError;
+
+    fn new(size_hint: Option<usize>) -> Self {
+        Self::with_params(size_hint.unwrap_or(1024), 2, 0)
+    }
+
+    fn request_with_hint(&mut self, hint: Self::Hint, page_id: PageId) -> Option<BufferFrame> {
+        match hint {
+            AccessHint::Random => self.request(page_id),
+            // Scan and one-off accesses do not count as references.
+            AccessHint::Sequential | AccessHint::OneShot => {
+                let frame = self.peek(page_id).cloned();
+                self.counters.record(frame.is_some());
+                frame
+            }
+            AccessHint::KeepHot => {
+                let frame = self.request(page_id)?;
+                self.make_hot(page_id);
+                Some(frame)
+            }
+        }
+    }
+
+    fn request(&mut self, page_id: PageId) -> Option<BufferFrame> {
+        let now = self.tick();
+        let entry = self.frames.remove(&page_id);
+        self.counters.record(entry.is_some());
+        let mut entry = entry?;
+        self.reference(&mut entry.history, &mut entry.last, now);
+        let frame = entry.frame.clone();
+        self.frames.insert(page_id, entry);
+        Some(frame)
+    }
+
+    fn peek(&self, page_id: PageId) -> Option<&BufferFrame> {
+        self.frames.get(&page_id).map(|entry| &entry.frame)
+    }
+
+    fn frames(&self) -> impl Iterator<Item = &BufferFrame> {
+        self.frames.values().map(|entry| &entry.frame)
+    }
+
+    fn eviction_order(&self, limit: usize) -> impl Iterator<Item = &BufferFrame> {
+        let mut entries: Vec<_> = self.frames.values().collect();
+        entries.sort_by_key(|entry| self.victim_key(entry));
+        entries.into_iter().take(limit).map(|entry| &entry.frame)
+    }
+
+    fn remove(&mut self, page_id: PageId) -> Option<BufferFrame> {
+        self.frames.remove(&page_id).map(|entry| entry.frame)
+    }
+
+    fn push(
+        &mut self,
+        page_id: PageId,
+        frame: BufferFrame,
+    ) -> Result<PushOutcome, Self::PushError> {
+        if self.frames.contains_key(&page_id) {
+            return Err(LruKError::AlreadyResident(page_id));
+        }
+
+        let now = self.tick();
+        let mut outcome = PushOutcome::Inserted;
+        if self.frames.len() >= self.capacity {
+            let Some(victim) = self.select_victim(now) else {
+                return Ok(PushOutcome::Full);
+            };
+            let entry = self.frames.remove(&victim).unwrap();
+            self.retained.put(victim, (entry.history, entry.last));
+            outcome = PushOutcome::Evicted {
+                page_id: victim,
+                frame: entry.frame,
+            };
+        }
+
+        let (mut history, mut last) = self
+            .retained
+            .pop(&page_id)
+            .unwrap_or_else(|| (VecDeque::with_capacity(self.k), now));
+        self.reference(&mut history, &mut last, now);
+        self.frames.insert(
+            page_id,
+            Entry {
+                frame,
+                history,
+                last,
+            },
+        );
+
+        Ok(outcome)
+    }
+
+    fn push_with_hint(
+        &mut self,
+        hint: Self::Hint,
+        page_id: PageId,
+        frame: BufferFrame,
+    ) -> Result<PushOutcome, Self::PushError> {
+        let outcome = self.push(page_id, frame)?;
+        if hint == AccessHint::KeepHot {
+            self.make_hot(page_id);
+        }
+        Ok(outcome)
+    }
+
+    fn counters(&self) -> PolicyCounters {
+        self.counters
+    }
+
+    fn usage(&self, page_id: PageId) -> Option<u64> {
+        let entry = self.frames.get(&page_id)?;
+        Some(entry.history.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::test_util::{create_mock_frame, evicted};
+
+    #[test]
+    fn test_lru_k_keeps_hot_pages_during_scan() {
+        let mut pool = LruK::new(Some(3));
+        for id in [1, 2] {
+            pool.push(PageId(id), create_mock_frame(id)).unwrap();
+            pool.request(PageId(id)).unwrap();
+        }
+
+        for id in 3..10 {
+            let outcome = pool.push(PageId(id), create_mock_frame(id)).unwrap();
+            if id > 3 {
+                assert_eq!(evicted(outcome), Some(PageId(id - 1)));
+            }
+        }
+        assert!(pool.peek(PageId(1)).is_some());
+        assert!(pool.peek(PageId(2)).is_some());
+    }
+
+    #[test]
+    fn test_lru_k_correlated_references_count_once() {
+        let mut pool = LruK::with_params(3, 2, 1);
+        pool.push(PageId(2), create_mock_frame(2)).unwrap();
+        pool.push(PageId(3), create_mock_frame(3)).unwrap();
+        pool.request(PageId(2)).unwrap();
+        pool.request(PageId(3)).unwrap();
+        pool.push(PageId(1), create_mock_frame(1)).unwrap();
+        pool.request(PageId(1)).unwrap();
+        pool.request(PageId(3)).unwrap();
+
+        // Page 1 was referenced twice in a burst, which only counts once, so it
+        // goes before page 2 even though page 2's references are older.
+        let outcome = pool.push(PageId(4), create_mock_frame(4)).unwrap();
+        assert_eq!(evicted(outcome), Some(PageId(1)));
+    }
+
+    #[test]
+    fn test_lru_k_skips_pinned_frames() {
+        let mut pool = LruK::new(Some(2));
+        pool.push(PageId(1), create_mock_frame(1)).unwrap();
+        pool.push(PageId(2), create_mock_frame(2)).unwrap();
+
+        pool.request(PageId(1)).unwrap().pin();
+        let outcome = pool.push(PageId(3), create_mock_frame(3)).unwrap();
+        assert_eq!(evicted(outcome), Some(PageId(2)));
+
+        pool.request(PageId(3)).unwrap().pin();
+        assert_eq!(
+            pool.push(PageId(4), create_mock_frame(4)),
+            Ok(PushOutcome::Full)
+        );
+    }
+}
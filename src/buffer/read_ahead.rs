@@ -0,0 +1,75 @@
+use crate::disk::PageId;
+
+use std::ops::Range;
+use std::sync::mpsc::Sender;
+
+use parking_lot::Mutex;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReadAheadOptions {
+    // Pages loaded ahead of a sequential scan.
+    pub window: u64,
+    // Consecutive page fetches that make an access pattern sequential.
+    pub trigger: usize,
+}
+
+impl Default for ReadAheadOptions {
+    fn default() -> Self {
+        Self {
+            window: 32,
+            trigger: 4,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Detector {
+    last: u64,
+    run: usize,
+    // End of the range already handed to the worker.
+    until: u64,
+}
+
+// Hands page ranges to the read-ahead worker and watches fetches for
+// sequential runs.
+#[derive(Debug)]
+pub(crate) struct ReadAhead {
+    options: ReadAheadOptions,
+    sender: Sender<Range<u64>>,
+    detector: Mutex<Detector>,
+}
+
+impl ReadAhead {
+    pub(crate) fn new(options: ReadAheadOptions, sender: Sender<Range<u64>>) -> Self {
+        Self {
+            options,
+            sender,
+            detector: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn request(&self, pages: Range<u64>) {
+        // The worker only goes away together with the pool.
+        let _ = self.sender.send(pages);
+    }
+
+    // Starts reading ahead once a scan is detected, and again whenever the
+    // scan gets within half a window of the pages already requested.
+    pub(crate) fn observe(&self, page_id: PageId) {
+        let mut detector = self.detector.lock();
+        if page_id.0 == detector.last + 1 {
+            detector.run += 1;
+        } else {
+            detector.run = 1;
+            detector.until = 0;
+        }
+        detector.last = page_id.0;
+
+        let window = self.options.window;
+        if detector.run >= self.options.trigger && page_id.0 + window / 2 >= detector.until {
+            let start = detector.until.max(page_id.0 + 1);
+            detector.until = page_id.0 + 1 + window;
+            self.request(start..detector.until);
+        }
+    }
+}
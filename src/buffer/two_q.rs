@@ -0,0 +1,240 @@
+use super::{lru_unpinned, AccessHint, BufferFrame, PolicyCounters, PoolAlgorithm, PushOutcome};
+use crate::disk::PageId;
+
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+use thiserror::Error;
+
+// 2Q (Johnson & Shasha), full version. New pages enter the `fifo` queue and
+// hits there are treated as correlated. Pages evicted from `fifo` are
+// remembered in `ghosts`; only a page that comes back while remembered is
+// admitted to the LRU `main` queue.
+#[derive(Debug)]
+pub struct TwoQueue {
+    capacity: usize,
+    fifo_capacity: usize,
+    fifo: LruCache<PageId, BufferFrame>,
+    main: LruCache<PageId, BufferFrame>,
+    ghosts: LruCache<PageId, ()>,
+    counters: PolicyCounters,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TwoQueueError {
+    #[error("page {0:?} is already resident")]
+    AlreadyResident(PageId),
+}
+
+impl TwoQueue {
+    // `fifo_capacity` and `ghost_capacity` are Kin and Kout from the paper.
+    pub fn with_params(size: usize, fifo_capacity: usize, ghost_capacity: usize) -> Self {
+        Self {
+            capacity: size,
+            fifo_capacity,
+            fifo: LruCache::unbounded(),
+            main: LruCache::unbounded(),
+            ghosts: LruCache::new(NonZeroUsize::new(ghost_capacity.max(1)).unwrap()),
+            counters: PolicyCounters::default(),
+        }
+    }
+
+    // Returns the victim and whether it came from `fifo`. Falls back to the
+    // other queue when every frame in the preferred one is pinned.
+    fn select_victim(&self) -> Option<(PageId, bool)> {
+        let from_fifo = lru_unpinned(&self.fifo).map(|page_id| (page_id, true));
+        let from_main = lru_unpinned(&self.main).map(|page_id| (page_id, false));
+        if self.fifo.len() > self.fifo_capacity || self.main.is_empty() {
+            from_fifo.or(from_main)
+        } else {
+            from_main.or(from_fifo)
+        }
+    }
+}
+
+impl PoolAlgorithm for TwoQueue {
+    type Hint = AccessHint;
+    type PushError = TwoQueueError;
+
+    fn new(size_hint: Option<usize>) -> Self {
+        let size = size_hint.unwrap_or(1024);
+        Self::with_params(size, (size / 4).max(1), (size / 2).max(1))
+    }
+
+    fn request_with_hint(&mut self, hint: Self::Hint, page_id: PageId) -> Option<BufferFrame> {
+        match hint {
+            AccessHint::Random => self.request(page_id),
+            AccessHint::Sequential | AccessHint::OneShot => {
+                let frame = self.peek(page_id).cloned();
+                self.counters.record(frame.is_some());
+                frame
+            }
+            AccessHint::KeepHot => {
+                if let Some(frame) = self.fifo.pop(&page_id) {
+                    self.main.put(page_id, frame);
+                }
+                self.request(page_id)
+            }
+        }
+    }
+
+    fn request(&mut self, page_id: PageId) -> Option<BufferFrame> {
+        let frame = self
+            .main
+            .get(&page_id)
+            .or_else(|| self.fifo.peek(&page_id))
+            .cloned();
+        self.counters.record(frame.is_some());
+        frame
+    }
+
+    fn peek(&self, page_id: PageId) -> Option<&BufferFrame> {
+        self.main
+            .peek(&page_id)
+            .or_else(|| self.fifo.peek(&page_id))
+    }
+
+    fn frames(&self) -> impl Iterator<Item = &BufferFrame> {
+        self.fifo
+            .iter()
+            .chain(self.main.iter())
+            .map(|(_, frame)| frame)
+    }
+
+    fn eviction_order(&self, limit: usize) -> impl Iterator<Item = &BufferFrame> {
+        let (first, second) = if self.fifo.len() > self.fifo_capacity || self.main.is_empty() {
+            (&self.fifo, &self.main)
+        } else {
+            (&self.main, &self.fifo)
+        };
+        first
+            .iter()
+            .rev()
+            .chain(second.iter().rev())
+            .take(limit)
+            .map(|(_, frame)| frame)
+    }
+
+    fn remove(&mut self, page_id: PageId) -> Option<BufferFrame> {
+        self.fifo.pop(&page_id).or_else(|| self.main.pop(&page_id))
+    }
+
+    fn push(
+        &mut self,
+        page_id: PageId,
+        frame: BufferFrame,
+    ) -> Result<PushOutcome, Self::PushError> {
+        if self.peek(page_id).is_some() {
+            return Err(TwoQueueError::AlreadyResident(page_id));
+        }
+
+        let mut outcome = PushOutcome::Inserted;
+        if self.fifo.len() + self.main.len() >= self.capacity {
+            let Some((victim, from_fifo)) = self.select_victim() else {
+                return Ok(PushOutcome::Full);
+            };
+            let old_frame = if from_fifo {
+                self.ghosts.put(victim, ());
+                self.fifo.pop(&victim)
+            } else {
+                self.main.pop(&victim)
+            };
+            outcome = PushOutcome::Evicted {
+                page_id: victim,
+                frame: old_frame.unwrap(),
+            };
+        }
+
+        if self.ghosts.pop(&page_id).is_some() {
+            self.main.put(page_id, frame);
+        } else {
+            self.fifo.put(page_id, frame);
+        }
+
+        Ok(outcome)
+    }
+
+    fn push_with_hint(
+        &mut self,
+        hint: Self::Hint,
+        page_id: PageId,
+        frame: BufferFrame,
+    ) -> Result<PushOutcome, Self::PushError> {
+        if matches!(hint, AccessHint::Sequential | AccessHint::OneShot)
+            && self.peek(page_id).is_none()
+        {
+            // Going back over a page is not a sign of reuse, so a remembered
+            // page is not admitted to `main`.
+            self.ghosts.pop(&page_id);
+        }
+        let outcome = self.push(page_id, frame)?;
+        match hint {
+            AccessHint::KeepHot => {
+                if let Some(frame) = self.fifo.pop(&page_id) {
+                    self.main.put(page_id, frame);
+                }
+            }
+            // The page is the first to leave `fifo`.
+            AccessHint::Sequential | AccessHint::OneShot => self.fifo.demote(&page_id),
+            AccessHint::Random => {}
+        }
+        Ok(outcome)
+    }
+
+    fn counters(&self) -> PolicyCounters {
+        self.counters
+    }
+
+    fn usage(&self, page_id: PageId) -> Option<u64> {
+        if self.main.contains(&page_id) {
+            Some(1)
+        } else {
+            self.fifo.contains(&page_id).then_some(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::test_util::{create_mock_frame, evicted};
+
+    #[test]
+    fn test_two_q_scans_evict_their_own_pages() {
+        let mut pool = TwoQueue::new(Some(4));
+        for id in 1..=3 {
+            pool.push(PageId(id), create_mock_frame(id)).unwrap();
+        }
+
+        // The second pass finds the scanned pages remembered in `ghosts`.
+        for _ in 0..2 {
+            for id in 10..20 {
+                let outcome = pool
+                    .push_with_hint(AccessHint::Sequential, PageId(id), create_mock_frame(id))
+                    .unwrap();
+                assert!(evicted(outcome).is_none_or(|page_id| page_id.0 >= 10));
+            }
+        }
+        assert!((1..=3).all(|id| pool.peek(PageId(id)).is_some()));
+        assert!(pool.main.is_empty());
+    }
+
+    #[test]
+    fn test_two_q_promotes_remembered_pages() {
+        let mut pool = TwoQueue::new(Some(4));
+        for id in 1..=4 {
+            pool.push(PageId(id), create_mock_frame(id)).unwrap();
+        }
+        let outcome = pool.push(PageId(5), create_mock_frame(5)).unwrap();
+        assert_eq!(evicted(outcome), Some(PageId(1)));
+
+        let outcome = pool.push(PageId(1), create_mock_frame(1)).unwrap();
+        assert_eq!(evicted(outcome), Some(PageId(2)));
+        assert!(pool.main.contains(&PageId(1)));
+
+        for id in 6..20 {
+            pool.push(PageId(id), create_mock_frame(id)).unwrap();
+        }
+        assert!(pool.peek(PageId(1)).is_some());
+    }
+}
@@ -0,0 +1,138 @@
+use super::{BufferPoolManager, PoolAlgorithm};
+use crate::store::PageStore;
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackgroundWriterOptions {
+    // Time between cleaning rounds.
+    pub interval: Duration,
+    // How many upcoming eviction candidates to look at per round.
+    pub lookahead: usize,
+    // Most pages written per round; bounds the writer's share of the disk.
+    pub max_pages: usize,
+}
+
+impl Default for BackgroundWriterOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(200),
+            lookahead: 64,
+            max_pages: 16,
+        }
+    }
+}
+
+// Runs a task on its own thread every `interval` until it is stopped or
+// dropped.
+pub(crate) struct Periodic {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    pub(crate) fn start(interval: Duration, mut task: impl FnMut() + Send + 'static) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let handle = std::thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                let (stopped, wakeup) = &*stop;
+                let mut stopped = stopped.lock();
+                while !*stopped {
+                    wakeup.wait_for(&mut stopped, interval);
+                    if *stopped {
+                        break;
+                    }
+                    task();
+                }
+            }
+        });
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    // Stops the thread and waits for its current round to finish.
+    pub(crate) fn stop(&mut self) {
+        let (stopped, wakeup) = &*self.stop;
+        *stopped.lock() = true;
+        wakeup.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Periodically writes out dirty frames that are about to be evicted, so that
+// `fetch_page` rarely has to write before it can read. Write errors are left
+// for the evicting thread to report, since the pages stay dirty.
+pub struct BackgroundWriter {
+    worker: Periodic,
+}
+
+impl BackgroundWriter {
+    pub fn start<Alg, S>(
+        bpm: Arc<BufferPoolManager<Alg, S>>,
+        options: BackgroundWriterOptions,
+    ) -> Self
+    where
+        Alg: PoolAlgorithm + 'static,
+        S: PageStore + 'static,
+    {
+        let worker = Periodic::start(options.interval, move || {
+            let _ = bpm.clean_ahead(options.lookahead, options.max_pages);
+        });
+        Self { worker }
+    }
+
+    // Stops the writer and waits for its current round to finish.
+    pub fn stop(mut self) {
+        self.worker.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::ClockSweep;
+    use crate::disk::DiskManager;
+    use crate::store::MemoryStore;
+
+    use std::time::Instant;
+
+    #[test]
+    fn test_background_writer_cleans_dirty_pages() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let pages: Vec<_> = (0..4).map(|_| disk.allocate_page().unwrap()).collect();
+        let bpm = Arc::new(BufferPoolManager::<ClockSweep, _>::new(disk, 4));
+        for &page_id in &pages {
+            bpm.fetch_page_write(page_id).unwrap()[0] = 1;
+        }
+
+        let options = BackgroundWriterOptions {
+            interval: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let writer = BackgroundWriter::start(Arc::clone(&bpm), options);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while bpm.resident_pages().iter().any(|page| page.is_dirty) {
+            assert!(Instant::now() < deadline, "pages were never cleaned");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        writer.stop();
+
+        assert_eq!(bpm.stats().disk.writes, 4);
+    }
+}
@@ -1,50 +1,900 @@
-use std::{
-    fs::{File, OpenOptions},
-    os::unix::prelude::FileExt,
-};
+use crate::page::{self, PageBuf, PAGE_ALIGN, PAGE_HEADER_SIZE};
+use crate::store::{read_full, write_full, FileStore, MmapStore, PageStore};
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use thiserror::Error;
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct PageId(pub u64);
 
-pub struct DiskManager {
+impl PageId {
+    pub const INVALID: PageId = PageId(0);
+}
+
+// Page 0 holds the superblock. Freed pages form a singly linked list whose
+// `next` pointer follows the 8-byte page header, so a free page has no page
+// LSN.
+const SUPERBLOCK_PAGE_ID: PageId = PageId(0);
+const SUPERBLOCK_MAGIC: [u8; 8] = *b"REINAHF\0";
+// Version 1 kept the free-list pointer at the start of the page, where the page
+// header now is.
+const SUPERBLOCK_VERSION: u32 = 2;
+const SUPERBLOCK_MAGIC_OFFSET: usize = 0;
+const SUPERBLOCK_VERSION_OFFSET: usize = 8;
+const SUPERBLOCK_FLAGS_OFFSET: usize = 12;
+const SUPERBLOCK_PAGE_SIZE_OFFSET: usize = 16;
+const SUPERBLOCK_PAGE_COUNT_OFFSET: usize = 24;
+const SUPERBLOCK_FREE_LIST_OFFSET: usize = 32;
+const SUPERBLOCK_SIZE: usize = 40;
+
+const FLAG_CHECKSUMS: u32 = 1 << 0;
+
+// When checksums are enabled, the last `CHECKSUM_SIZE` bytes of every page hold
+// the CRC32C of the rest of the page and are overwritten by `write_page`.
+pub const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiskOptions {
+    pub page_size: u64,
+    // Only takes effect when a new heap file is created; existing files keep
+    // the setting recorded in their superblock.
+    pub checksums: bool,
+    // Opens the file with `O_DIRECT` in `from_path_with_options`, leaving the
+    // buffer pool as the only cache. The page size must be a multiple of
+    // `PAGE_ALIGN`.
+    pub direct_io: bool,
+}
+
+impl DiskOptions {
+    pub const fn new(page_size: u64) -> Self {
+        Self {
+            page_size,
+            checksums: false,
+            direct_io: false,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DiskManagerError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("not a heap file (bad magic number)")]
+    InvalidMagic,
+
+    #[error("unsupported heap file version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("superblock is truncated ({0} bytes)")]
+    TruncatedSuperblock(u64),
+
+    #[error("page size {0} is too small to hold the superblock")]
+    InvalidPageSize(u64),
+
+    #[error(
+        "page size {0} is not a multiple of {} as direct I/O requires",
+        PAGE_ALIGN
+    )]
+    UnalignedPageSize(u64),
+
+    #[error("page size mismatch: expected {expected}, file has {found}")]
+    PageSizeMismatch { expected: u64, found: u64 },
+
+    #[error("page {0:?} is not allocated")]
+    PageNotAllocated(PageId),
+
+    #[error("free list is corrupted at page {0:?}")]
+    CorruptFreeList(PageId),
+
+    #[error("page {page_id:?} is truncated ({len} bytes on disk)")]
+    TruncatedPage { page_id: PageId, len: usize },
+
+    #[error(
+        "page {page_id:?} is corrupted: stored checksum {stored:#010x}, computed {computed:#010x}"
+    )]
+    ChecksumMismatch {
+        page_id: PageId,
+        stored: u32,
+        computed: u32,
+    },
+}
+
+#[derive(Debug)]
+struct Allocation {
+    page_count: u64,
+    free_list_head: PageId,
+    // The pages on the free list, to catch double frees.
+    free: HashSet<PageId>,
+}
+
+impl Default for Allocation {
+    fn default() -> Self {
+        Self {
+            page_count: 1,
+            free_list_head: PageId::INVALID,
+            free: HashSet::new(),
+        }
+    }
+}
+
+// Page I/O issued through a `DiskManager`. Superblock updates are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub syncs: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub read_time: Duration,
+    pub write_time: Duration,
+    pub sync_time: Duration,
+}
+
+#[derive(Debug, Default)]
+struct IoCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    syncs: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_nanos: AtomicU64,
+    write_nanos: AtomicU64,
+    sync_nanos: AtomicU64,
+}
+
+fn record(count: &AtomicU64, nanos: &AtomicU64, ops: u64, start: Instant) {
+    count.fetch_add(ops, Ordering::Relaxed);
+    nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+}
+
+pub struct DiskManager<S: PageStore = FileStore> {
     page_size: u64,
-    heap_file: File,
+    checksums: bool,
+    // Transfers that are not aligned for `O_DIRECT` go through a bounce buffer.
+    direct_io: bool,
+    store: S,
+    allocation: Mutex<Allocation>,
+    io: IoCounters,
 }
 
 impl DiskManager {
-    pub const fn from_file(file: File, page_size: u64) -> Self {
-        Self {
+    pub fn from_file(file: File, page_size: u64) -> Result<Self, DiskManagerError> {
+        Self::from_store(FileStore::from_file(file), page_size)
+    }
+    pub fn from_file_with_options(
+        file: File,
+        options: DiskOptions,
+    ) -> Result<Self, DiskManagerError> {
+        Self::from_store_with_options(FileStore::from_file(file), options)
+    }
+    pub fn from_path(
+        path: impl AsRef<std::path::Path>,
+        page_size: u64,
+    ) -> Result<Self, DiskManagerError> {
+        Self::from_path_with_options(path, DiskOptions::new(page_size))
+    }
+    pub fn from_path_with_options(
+        path: impl AsRef<std::path::Path>,
+        options: DiskOptions,
+    ) -> Result<Self, DiskManagerError> {
+        let store = if options.direct_io {
+            FileStore::from_path_direct(path)?
+        } else {
+            FileStore::from_path(path)?
+        };
+        Self::from_store_with_options(store, options)
+    }
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self, DiskManagerError> {
+        Self::open_store(FileStore::open(path)?)
+    }
+}
+
+// Serves page reads straight from a memory mapping of the heap file.
+impl DiskManager<MmapStore> {
+    pub fn map_path(
+        path: impl AsRef<std::path::Path>,
+        page_size: u64,
+    ) -> Result<Self, DiskManagerError> {
+        Self::map_path_with_options(path, DiskOptions::new(page_size))
+    }
+    // `direct_io` does not apply to mapped files and is ignored.
+    pub fn map_path_with_options(
+        path: impl AsRef<std::path::Path>,
+        options: DiskOptions,
+    ) -> Result<Self, DiskManagerError> {
+        let options = DiskOptions {
+            direct_io: false,
+            ..options
+        };
+        Self::from_store_with_options(MmapStore::from_path(path)?, options)
+    }
+    pub fn open_mapped(path: impl AsRef<std::path::Path>) -> Result<Self, DiskManagerError> {
+        Self::open_store(MmapStore::open(path)?)
+    }
+}
+
+impl<S: PageStore> DiskManager<S> {
+    pub fn from_store(store: S, page_size: u64) -> Result<Self, DiskManagerError> {
+        Self::from_store_with_options(store, DiskOptions::new(page_size))
+    }
+    pub fn from_store_with_options(
+        store: S,
+        options: DiskOptions,
+    ) -> Result<Self, DiskManagerError> {
+        let page_size = options.page_size;
+        if page_size < SUPERBLOCK_SIZE as u64 {
+            return Err(DiskManagerError::InvalidPageSize(page_size));
+        }
+        if options.direct_io && !page_size.is_multiple_of(PAGE_ALIGN as u64) {
+            return Err(DiskManagerError::UnalignedPageSize(page_size));
+        }
+
+        let mut disk_manager = Self {
             page_size,
-            heap_file: file,
+            checksums: options.checksums,
+            direct_io: options.direct_io,
+            store,
+            allocation: Mutex::default(),
+            io: IoCounters::default(),
+        };
+
+        if disk_manager.store.is_empty()? {
+            disk_manager.write_superblock(&disk_manager.allocation.lock())?;
+        } else {
+            let found = disk_manager.read_superblock()?;
+            if found != page_size {
+                return Err(DiskManagerError::PageSizeMismatch {
+                    expected: page_size,
+                    found,
+                });
+            }
         }
+
+        Ok(disk_manager)
     }
-    pub fn from_path(path: impl AsRef<std::path::Path>, page_size: u64) -> std::io::Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)?;
-        Ok(Self::from_file(file, page_size))
+    pub fn open_store(store: S) -> Result<Self, DiskManagerError> {
+        let mut disk_manager = Self {
+            page_size: 0,
+            checksums: false,
+            direct_io: false,
+            store,
+            allocation: Mutex::default(),
+            io: IoCounters::default(),
+        };
+        disk_manager.page_size = disk_manager.read_superblock()?;
+        Ok(disk_manager)
     }
     pub const fn get_page_size(&self) -> u64 {
         self.page_size
     }
+    pub fn get_page_count(&self) -> u64 {
+        self.allocation.lock().page_count
+    }
+    pub const fn has_checksums(&self) -> bool {
+        self.checksums
+    }
+
+    // Loads the allocation state from the superblock and returns the page size
+    // recorded in it.
+    fn read_superblock(&mut self) -> Result<u64, DiskManagerError> {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        let len = self.read_at(0, &mut superblock)?;
+        if superblock[SUPERBLOCK_MAGIC_OFFSET..SUPERBLOCK_MAGIC_OFFSET + 8] != SUPERBLOCK_MAGIC {
+            return Err(DiskManagerError::InvalidMagic);
+        }
+        if len < SUPERBLOCK_SIZE {
+            return Err(DiskManagerError::TruncatedSuperblock(len as u64));
+        }
+
+        let version = read_u32(&superblock, SUPERBLOCK_VERSION_OFFSET);
+        if version != SUPERBLOCK_VERSION {
+            return Err(DiskManagerError::UnsupportedVersion(version));
+        }
+
+        let page_size = read_u64(&superblock, SUPERBLOCK_PAGE_SIZE_OFFSET);
+        if page_size < SUPERBLOCK_SIZE as u64 {
+            return Err(DiskManagerError::InvalidPageSize(page_size));
+        }
+
+        self.checksums = read_u32(&superblock, SUPERBLOCK_FLAGS_OFFSET) & FLAG_CHECKSUMS != 0;
+        let allocation = self.allocation.get_mut();
+        allocation.page_count = read_u64(&superblock, SUPERBLOCK_PAGE_COUNT_OFFSET);
+        allocation.free_list_head = PageId(read_u64(&superblock, SUPERBLOCK_FREE_LIST_OFFSET));
+        self.page_size = page_size;
+        self.read_free_list()?;
+        Ok(page_size)
+    }
+    // Walks the free list. A page that is out of range or already on the list
+    // means the list is corrupted; following it would hand out pages twice.
+    fn read_free_list(&mut self) -> Result<(), DiskManagerError> {
+        let mut free = HashSet::new();
+        let (page_count, mut page_id) = {
+            let allocation = self.allocation.get_mut();
+            (allocation.page_count, allocation.free_list_head)
+        };
+        while page_id != PageId::INVALID {
+            if page_id == SUPERBLOCK_PAGE_ID || page_id.0 >= page_count || !free.insert(page_id) {
+                return Err(DiskManagerError::CorruptFreeList(page_id));
+            }
+            page_id = self.read_next_free(page_id)?;
+        }
+        self.allocation.get_mut().free = free;
+        Ok(())
+    }
+    fn write_superblock(&self, allocation: &Allocation) -> std::io::Result<()> {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        superblock[SUPERBLOCK_MAGIC_OFFSET..SUPERBLOCK_MAGIC_OFFSET + 8]
+            .copy_from_slice(&SUPERBLOCK_MAGIC);
+        write_u32(
+            &mut superblock,
+            SUPERBLOCK_VERSION_OFFSET,
+            SUPERBLOCK_VERSION,
+        );
+        let flags = if self.checksums { FLAG_CHECKSUMS } else { 0 };
+        write_u32(&mut superblock, SUPERBLOCK_FLAGS_OFFSET, flags);
+        write_u64(&mut superblock, SUPERBLOCK_PAGE_SIZE_OFFSET, self.page_size);
+        write_u64(
+            &mut superblock,
+            SUPERBLOCK_PAGE_COUNT_OFFSET,
+            allocation.page_count,
+        );
+        write_u64(
+            &mut superblock,
+            SUPERBLOCK_FREE_LIST_OFFSET,
+            allocation.free_list_head.0,
+        );
+        self.write_at(self.page_size * SUPERBLOCK_PAGE_ID.0, &superblock)
+    }
+
+    pub fn allocate_page(&self) -> Result<PageId, DiskManagerError> {
+        let mut allocation = self.allocation.lock();
+        let page_id = if allocation.free_list_head != PageId::INVALID {
+            let page_id = allocation.free_list_head;
+            allocation.free_list_head = self.read_next_free(page_id)?;
+            allocation.free.remove(&page_id);
+            page_id
+        } else {
+            let page_id = PageId(allocation.page_count);
+            if self.checksums {
+                // Seal the new page so that reading it back checks out like
+                // any other page. Freed pages were sealed when freed.
+                let page = PageBuf::zeroed(self.page_size as usize);
+                self.write_checked(&[(page_id, &page)])?;
+            }
+            allocation.page_count += 1;
+            page_id
+        };
+
+        self.write_superblock(&allocation)?;
+        Ok(page_id)
+    }
+    fn read_next_free(&self, page_id: PageId) -> std::io::Result<PageId> {
+        let mut next = [0; 8];
+        let offset = self.page_size * page_id.0 + PAGE_HEADER_SIZE as u64;
+        self.read_at(offset, &mut next)?;
+        Ok(PageId(u64::from_le_bytes(next)))
+    }
+    pub fn deallocate_page(&self, page_id: PageId) -> Result<(), DiskManagerError> {
+        let mut allocation = self.allocation.lock();
+        if page_id == SUPERBLOCK_PAGE_ID
+            || page_id.0 >= allocation.page_count
+            || allocation.free.contains(&page_id)
+        {
+            return Err(DiskManagerError::PageNotAllocated(page_id));
+        }
+
+        // A free page is written in full, so that it still passes its checksum
+        // and has no page LSN, and holds the next free page after the header.
+        let mut page = PageBuf::zeroed(self.page_size as usize);
+        page[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8]
+            .copy_from_slice(&allocation.free_list_head.0.to_le_bytes());
+        self.write_checked(&[(page_id, &page)])?;
+        let next = std::mem::replace(&mut allocation.free_list_head, page_id);
+        allocation.free.insert(page_id);
+        if let Err(e) = self.write_superblock(&allocation) {
+            // The page stays allocated, as the superblock on disk says.
+            allocation.free_list_head = next;
+            allocation.free.remove(&page_id);
+            return Err(e.into());
+        }
+        Ok(())
+    }
 
+    // Reads until `buf` is full or EOF is reached and returns the number of
+    // bytes read.
     pub(crate) fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.heap_file.read_at(buf, offset)
+        if !self.direct_io || page::is_aligned(offset, buf) {
+            return read_full(&self.store, offset, buf);
+        }
+        let (start, mut bounce) = bounce_buffer(offset, buf.len());
+        let skip = (offset - start) as usize;
+        let len = read_full(&self.store, start, &mut bounce)?
+            .saturating_sub(skip)
+            .min(buf.len());
+        buf[..len].copy_from_slice(&bounce[skip..skip + len]);
+        Ok(len)
     }
-    pub(crate) fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
-        self.heap_file.write_at(buf, offset)
+    pub(crate) fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        if !self.direct_io || page::is_aligned(offset, buf) {
+            return write_full(&self.store, offset, buf);
+        }
+        // Partially covered blocks are read back first. Only the superblock is
+        // written like that, under the allocation lock.
+        let (start, mut bounce) = bounce_buffer(offset, buf.len());
+        let skip = (offset - start) as usize;
+        if skip != 0 || bounce.len() != buf.len() {
+            read_full(&self.store, start, &mut bounce)?;
+        }
+        bounce[skip..skip + buf.len()].copy_from_slice(buf);
+        write_full(&self.store, start, &bounce)
     }
-    pub fn read_page(&self, page_id: PageId, data: &mut [u8]) -> std::io::Result<usize> {
-        let offset = self.page_size * page_id.0;
-        self.read_at(offset, data)
+    fn check_page(&self, page_id: PageId, data: &[u8]) -> Result<(), DiskManagerError> {
+        assert_eq!(data.len() as u64, self.page_size, "buffer is not one page");
+        let allocation = self.allocation.lock();
+        if page_id == SUPERBLOCK_PAGE_ID
+            || page_id.0 >= allocation.page_count
+            || allocation.free.contains(&page_id)
+        {
+            return Err(DiskManagerError::PageNotAllocated(page_id));
+        }
+        Ok(())
     }
-    pub fn write_page(&self, page_id: PageId, data: &[u8]) -> std::io::Result<usize> {
+    // Allocated pages that lie beyond the end of the file have never been
+    // written and read back as zeroes. A page cut short by the end of the file
+    // is reported as truncated.
+    pub fn read_page(&self, page_id: PageId, data: &mut [u8]) -> Result<(), DiskManagerError> {
+        self.check_page(page_id, data)?;
+
         let offset = self.page_size * page_id.0;
-        self.write_at(offset, data)
+        let start = Instant::now();
+        let len = self.read_at(offset, data)?;
+        record(&self.io.reads, &self.io.read_nanos, 1, start);
+        self.io.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+        self.verify_page(page_id, data, len)
+    }
+    // Reads several pages with one batch submission to the store. Fails with
+    // the first error, after which the contents of every buffer are undefined.
+    pub fn read_pages(&self, pages: &mut [(PageId, &mut [u8])]) -> Result<(), DiskManagerError> {
+        for (page_id, data) in pages.iter() {
+            self.check_page(*page_id, data)?;
+        }
+
+        let mut reads: Vec<_> = pages
+            .iter_mut()
+            .map(|(page_id, data)| (self.page_size * page_id.0, &mut **data))
+            .collect();
+        let start = Instant::now();
+        let lens = if self.direct_io
+            && reads
+                .iter()
+                .any(|(offset, buf)| !page::is_aligned(*offset, buf))
+        {
+            reads
+                .iter_mut()
+                .map(|(offset, buf)| self.read_at(*offset, buf))
+                .collect::<std::io::Result<_>>()?
+        } else {
+            self.store.read_batch(&mut reads)?
+        };
+        record(
+            &self.io.reads,
+            &self.io.read_nanos,
+            lens.len() as u64,
+            start,
+        );
+        let bytes: usize = lens.iter().sum();
+        self.io
+            .bytes_read
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+
+        for ((page_id, data), len) in pages.iter_mut().zip(lens) {
+            self.verify_page(*page_id, data, len)?;
+        }
+        Ok(())
+    }
+    fn verify_page(
+        &self,
+        page_id: PageId,
+        data: &mut [u8],
+        len: usize,
+    ) -> Result<(), DiskManagerError> {
+        if len == 0 {
+            data.fill(0);
+            return Ok(());
+        }
+        if len < data.len() {
+            return Err(DiskManagerError::TruncatedPage { page_id, len });
+        }
+
+        if self.checksums {
+            let (body, trailer) = data.split_at(data.len() - CHECKSUM_SIZE);
+            let stored = u32::from_le_bytes(trailer.try_into().unwrap());
+            let computed = crc32c::crc32c(body);
+            if stored != computed {
+                return Err(DiskManagerError::ChecksumMismatch {
+                    page_id,
+                    stored,
+                    computed,
+                });
+            }
+        }
+
+        Ok(())
+    }
+    pub fn write_page(&self, page_id: PageId, data: &[u8]) -> Result<(), DiskManagerError> {
+        self.write_pages(&[(page_id, data)])
+    }
+    // Writes several pages with one batch submission to the store.
+    pub fn write_pages(&self, pages: &[(PageId, &[u8])]) -> Result<(), DiskManagerError> {
+        for &(page_id, data) in pages {
+            self.check_page(page_id, data)?;
+        }
+        self.write_checked(pages)
+    }
+    // `write_pages` without the allocation check, for callers that already
+    // hold the allocation lock.
+    fn write_checked(&self, pages: &[(PageId, &[u8])]) -> Result<(), DiskManagerError> {
+        let sealed: Vec<_> = pages.iter().map(|&(_, data)| self.seal(data)).collect();
+        let writes: Vec<_> = pages
+            .iter()
+            .zip(&sealed)
+            .map(|(&(page_id, data), sealed)| {
+                (
+                    self.page_size * page_id.0,
+                    sealed.as_deref().unwrap_or(data),
+                )
+            })
+            .collect();
+        let start = Instant::now();
+        let unaligned = || {
+            writes
+                .iter()
+                .any(|&(offset, data)| !page::is_aligned(offset, data))
+        };
+        if writes.len() == 1 || self.direct_io && unaligned() {
+            for &(offset, data) in &writes {
+                self.write_at(offset, data)?;
+            }
+        } else {
+            self.store.write_batch(&writes)?;
+        }
+        record(
+            &self.io.writes,
+            &self.io.write_nanos,
+            writes.len() as u64,
+            start,
+        );
+        let bytes: usize = writes.iter().map(|(_, data)| data.len()).sum();
+        self.io
+            .bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok(())
+    }
+    // Returns a copy of the page with its checksum trailer filled in if the
+    // file has checksums.
+    fn seal(&self, data: &[u8]) -> Option<PageBuf> {
+        if !self.checksums {
+            return None;
+        }
+        let mut page = PageBuf::from(data);
+        let body_len = page.len() - CHECKSUM_SIZE;
+        let checksum = crc32c::crc32c(&page[..body_len]);
+        page[body_len..].copy_from_slice(&checksum.to_le_bytes());
+        Some(page)
     }
     pub fn sync(&self) -> std::io::Result<()> {
-        self.heap_file.sync_all()
+        let start = Instant::now();
+        self.store.sync()?;
+        record(&self.io.syncs, &self.io.sync_nanos, 1, start);
+        Ok(())
+    }
+    pub fn stats(&self) -> DiskStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        DiskStats {
+            reads: load(&self.io.reads),
+            writes: load(&self.io.writes),
+            syncs: load(&self.io.syncs),
+            bytes_read: load(&self.io.bytes_read),
+            bytes_written: load(&self.io.bytes_written),
+            read_time: Duration::from_nanos(load(&self.io.read_nanos)),
+            write_time: Duration::from_nanos(load(&self.io.write_nanos)),
+            sync_time: Duration::from_nanos(load(&self.io.sync_nanos)),
+        }
+    }
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+// Returns an aligned buffer covering `len` bytes at `offset`, and the offset
+// at which it starts.
+fn bounce_buffer(offset: u64, len: usize) -> (u64, PageBuf) {
+    let align = PAGE_ALIGN as u64;
+    let start = offset / align * align;
+    let end = (offset + len as u64).div_ceil(align) * align;
+    (start, PageBuf::zeroed((end - start) as usize))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_allocate_reuses_freed_pages_across_reopen() {
+        let store = MemoryStore::new();
+
+        let disk = DiskManager::from_store(store.clone(), 4096).unwrap();
+        assert_eq!(disk.allocate_page().unwrap(), PageId(1));
+        assert_eq!(disk.allocate_page().unwrap(), PageId(2));
+        assert_eq!(disk.allocate_page().unwrap(), PageId(3));
+
+        disk.deallocate_page(PageId(1)).unwrap();
+        disk.deallocate_page(PageId(3)).unwrap();
+        drop(disk);
+
+        let disk = DiskManager::from_store(store, 4096).unwrap();
+        assert_eq!(disk.get_page_count(), 4);
+        assert_eq!(disk.allocate_page().unwrap(), PageId(3));
+        assert_eq!(disk.allocate_page().unwrap(), PageId(1));
+        assert_eq!(disk.allocate_page().unwrap(), PageId(4));
+    }
+
+    #[test]
+    fn test_double_free_is_rejected() {
+        let store = MemoryStore::new();
+
+        let disk = DiskManager::from_store(store.clone(), 4096).unwrap();
+        let first = disk.allocate_page().unwrap();
+        let second = disk.allocate_page().unwrap();
+        disk.deallocate_page(first).unwrap();
+        disk.deallocate_page(second).unwrap();
+        for page_id in [first, second] {
+            assert!(matches!(
+                disk.deallocate_page(page_id),
+                Err(DiskManagerError::PageNotAllocated(id)) if id == page_id
+            ));
+        }
+        drop(disk);
+
+        // The free pages are known again after reopening.
+        let disk = DiskManager::from_store(store.clone(), 4096).unwrap();
+        assert!(disk.deallocate_page(first).is_err());
+        let mut page = vec![0; 4096];
+        assert!(matches!(
+            disk.read_page(first, &mut page),
+            Err(DiskManagerError::PageNotAllocated(id)) if id == first
+        ));
+        assert!(matches!(
+            disk.write_page(second, &page),
+            Err(DiskManagerError::PageNotAllocated(id)) if id == second
+        ));
+        assert_eq!(disk.allocate_page().unwrap(), second);
+        assert_eq!(disk.allocate_page().unwrap(), first);
+        assert_eq!(disk.allocate_page().unwrap(), PageId(3));
+        disk.deallocate_page(first).unwrap();
+        drop(disk);
+
+        // A free list that loops back on itself is refused.
+        store
+            .write_at(4096 + PAGE_HEADER_SIZE as u64, &first.0.to_le_bytes())
+            .unwrap();
+        assert!(matches!(
+            DiskManager::open_store(store),
+            Err(DiskManagerError::CorruptFreeList(id)) if id == first
+        ));
+    }
+
+    #[test]
+    fn test_checksum_detects_corruption() {
+        let store = MemoryStore::new();
+
+        let options = DiskOptions {
+            page_size: 4096,
+            checksums: true,
+            direct_io: false,
+        };
+        let disk = DiskManager::from_store_with_options(store.clone(), options).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page(page_id, &vec![0x5a; 4096]).unwrap();
+        drop(disk);
+
+        let disk = DiskManager::open_store(store).unwrap();
+        assert!(disk.has_checksums());
+        let mut page = vec![0; 4096];
+        disk.read_page(page_id, &mut page).unwrap();
+        assert_eq!(page[..4096 - CHECKSUM_SIZE], [0x5a; 4096 - CHECKSUM_SIZE]);
+
+        disk.write_at(4096 * page_id.0 + 100, &[0xff]).unwrap();
+        assert!(matches!(
+            disk.read_page(page_id, &mut page),
+            Err(DiskManagerError::ChecksumMismatch { page_id: id, .. }) if id == page_id
+        ));
+
+        // A page that was written is never mistaken for a blank one.
+        disk.write_at(4096 * page_id.0, &[0; 4096]).unwrap();
+        assert!(matches!(
+            disk.read_page(page_id, &mut page),
+            Err(DiskManagerError::ChecksumMismatch { page_id: id, .. }) if id == page_id
+        ));
+
+        // Freeing the page rewrites it with a valid checksum.
+        disk.deallocate_page(page_id).unwrap();
+        assert_eq!(disk.allocate_page().unwrap(), page_id);
+        disk.read_page(page_id, &mut page).unwrap();
+        assert_eq!(page::page_lsn(&page), crate::wal::Lsn::INVALID);
+    }
+
+    #[test]
+    fn test_batched_page_io() {
+        let options = DiskOptions {
+            page_size: 4096,
+            checksums: true,
+            direct_io: false,
+        };
+        let disk = DiskManager::from_store_with_options(MemoryStore::new(), options).unwrap();
+        let pages: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        // Each new page was sealed once.
+        assert_eq!(disk.stats().writes, 3);
+        let data: Vec<_> = (1..=3u8).map(|b| vec![b; 4096]).collect();
+
+        let writes: Vec<_> = pages
+            .iter()
+            .zip(&data)
+            .map(|(&id, d)| (id, &d[..]))
+            .collect();
+        disk.write_pages(&writes[1..]).unwrap();
+
+        let mut bufs = vec![vec![0xcc; 4096]; 3];
+        let mut reads: Vec<_> = pages
+            .iter()
+            .zip(&mut bufs)
+            .map(|(&id, buf)| (id, &mut buf[..]))
+            .collect();
+        disk.read_pages(&mut reads).unwrap();
+        assert_eq!(bufs[0][..4096 - CHECKSUM_SIZE], [0; 4096 - CHECKSUM_SIZE]);
+        assert_eq!(
+            bufs[2][..4096 - CHECKSUM_SIZE],
+            data[2][..4096 - CHECKSUM_SIZE]
+        );
+
+        let mut page = vec![0; 4096];
+        let mut reads = vec![(pages[0], &mut page[..]), (PageId(9), &mut bufs[0][..])];
+        assert!(matches!(
+            disk.read_pages(&mut reads),
+            Err(DiskManagerError::PageNotAllocated(PageId(9)))
+        ));
+        assert_eq!(disk.stats().writes, 5);
+    }
+
+    #[test]
+    fn test_page_io_bounds() {
+        let disk = DiskManager::from_store(MemoryStore::new(), 4096).unwrap();
+        let mut page = vec![0xcc; 4096];
+        assert!(matches!(
+            disk.read_page(PageId(1), &mut page),
+            Err(DiskManagerError::PageNotAllocated(PageId(1)))
+        ));
+        assert!(matches!(
+            disk.write_page(PageId(0), &page),
+            Err(DiskManagerError::PageNotAllocated(PageId(0)))
+        ));
+
+        let first = disk.allocate_page().unwrap();
+        let second = disk.allocate_page().unwrap();
+        disk.read_page(second, &mut page).unwrap();
+        assert_eq!(page, vec![0; 4096]);
+
+        disk.write_page(first, &vec![0x11; 4096]).unwrap();
+        disk.store().set_len(4096 * 2 - 100).unwrap();
+        assert!(matches!(
+            disk.read_page(first, &mut page),
+            Err(DiskManagerError::TruncatedPage { page_id, len: 3996 }) if page_id == first
+        ));
+    }
+
+    #[test]
+    fn test_superblock_validation() {
+        let store = MemoryStore::new();
+
+        drop(DiskManager::from_store(store.clone(), 4096).unwrap());
+        let disk = DiskManager::open_store(store.clone()).unwrap();
+        assert_eq!(disk.get_page_size(), 4096);
+        assert!(matches!(
+            DiskManager::from_store(store, 8192),
+            Err(DiskManagerError::PageSizeMismatch {
+                expected: 8192,
+                found: 4096
+            })
+        ));
+
+        let store = MemoryStore::new();
+        store.write_at(0, &[0xab; 4096]).unwrap();
+        assert!(matches!(
+            DiskManager::from_store(store, 4096),
+            Err(DiskManagerError::InvalidMagic)
+        ));
+
+        let store = MemoryStore::new();
+        store.write_at(0, &SUPERBLOCK_MAGIC).unwrap();
+        assert!(matches!(
+            DiskManager::open_store(store),
+            Err(DiskManagerError::TruncatedSuperblock(8))
+        ));
+    }
+
+    // Unaligned transfers, like the superblock and free-list pointers, are
+    // bounced through aligned buffers.
+    fn check_direct_io<S: PageStore>(disk: DiskManager<S>) {
+        let first = disk.allocate_page().unwrap();
+        let second = disk.allocate_page().unwrap();
+        let mut aligned = PageBuf::zeroed(4096);
+        aligned.fill(0x5a);
+        let mut unaligned = vec![0; 4097];
+        unaligned[1..].fill(0xa5);
+        disk.write_pages(&[(first, &aligned), (second, &unaligned[1..])])
+            .unwrap();
+
+        disk.deallocate_page(first).unwrap();
+        assert_eq!(disk.allocate_page().unwrap(), first);
+
+        let mut page = PageBuf::zeroed(4096);
+        disk.read_page(second, &mut page).unwrap();
+        assert_eq!(page[..], unaligned[1..]);
+        disk.read_pages(&mut [(first, &mut unaligned[1..])])
+            .unwrap();
+        assert!(unaligned[1..].iter().all(|&b| b == 0));
+        assert_eq!(disk.get_page_count(), 3);
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let options = DiskOptions {
+            page_size: 4096,
+            checksums: false,
+            direct_io: true,
+        };
+        assert!(matches!(
+            DiskManager::from_store_with_options(
+                MemoryStore::new(),
+                DiskOptions {
+                    page_size: 1000,
+                    ..options
+                }
+            ),
+            Err(DiskManagerError::UnalignedPageSize(1000))
+        ));
+        check_direct_io(DiskManager::from_store_with_options(MemoryStore::new(), options).unwrap());
+
+        let path = std::env::temp_dir().join(format!("reina-direct-{}", std::process::id()));
+        match DiskManager::from_path_with_options(&path, options) {
+            Ok(disk) => check_direct_io(disk),
+            // Some file systems, like tmpfs, do not support O_DIRECT.
+            Err(DiskManagerError::Io(e)) if e.raw_os_error() == Some(libc::EINVAL) => {}
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(&path);
     }
 }
@@ -1,4 +1,6 @@
-#![feature(generic_const_exprs)]
-
 pub mod buffer;
 pub mod disk;
+pub mod page;
+pub mod recovery;
+pub mod store;
+pub mod wal;
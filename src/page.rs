@@ -0,0 +1,125 @@
+use crate::wal::Lsn;
+
+use std::alloc::{self, Layout};
+use std::ptr::NonNull;
+
+// Alignment of page buffers. Direct I/O needs buffers, offsets and lengths
+// aligned to the logical block size of the device, which is at most this on
+// the systems we run on.
+pub const PAGE_ALIGN: usize = 4096;
+
+// Every page starts with a header holding the LSN of the last log record that
+// changed it. The buffer pool does not write a page until that record is
+// durable.
+pub const PAGE_HEADER_SIZE: usize = 8;
+
+pub fn page_lsn(page: &[u8]) -> Lsn {
+    Lsn(u64::from_le_bytes(page[..8].try_into().unwrap()))
+}
+
+pub fn set_page_lsn(page: &mut [u8], lsn: Lsn) {
+    page[..8].copy_from_slice(&lsn.0.to_le_bytes());
+}
+
+// A heap buffer aligned to `PAGE_ALIGN`, usable with direct I/O.
+pub struct PageBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: `PageBuf` owns its allocation like a `Vec<u8>`.
+unsafe impl Send for PageBuf {}
+unsafe impl Sync for PageBuf {}
+
+impl PageBuf {
+    pub fn zeroed(len: usize) -> Self {
+        let layout = Self::layout(len);
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            alloc::handle_alloc_error(layout);
+        };
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len.max(1), PAGE_ALIGN).expect("page buffer too large")
+    }
+}
+
+// Whether a transfer of `buf` at `offset` can be issued with direct I/O as is.
+pub(crate) fn is_aligned(offset: u64, buf: &[u8]) -> bool {
+    offset.is_multiple_of(PAGE_ALIGN as u64)
+        && buf.len().is_multiple_of(PAGE_ALIGN)
+        && (buf.as_ptr() as usize).is_multiple_of(PAGE_ALIGN)
+}
+
+impl Drop for PageBuf {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `zeroed` with the same layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+impl std::ops::Deref for PageBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the allocation holds `len` initialized bytes.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl std::ops::DerefMut for PageBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in `deref`, and `&mut self` makes the access unique.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl From<&[u8]> for PageBuf {
+    fn from(data: &[u8]) -> Self {
+        let mut buf = Self::zeroed(data.len());
+        buf.copy_from_slice(data);
+        buf
+    }
+}
+
+impl Clone for PageBuf {
+    fn clone(&self) -> Self {
+        Self::from(&self[..])
+    }
+}
+
+impl PartialEq for PageBuf {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for PageBuf {}
+
+impl std::fmt::Debug for PageBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PageBuf").field("len", &self.len).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_buf_is_aligned_and_zeroed() {
+        for len in [0, 16, 4096, 3 * 4096] {
+            let mut buf = PageBuf::zeroed(len);
+            assert_eq!(buf.as_ptr() as usize % PAGE_ALIGN, 0);
+            assert!(buf.iter().all(|&b| b == 0));
+
+            buf.fill(7);
+            let copy = buf.clone();
+            assert_eq!(copy, buf);
+            assert_eq!(copy.as_ptr() as usize % PAGE_ALIGN, 0);
+        }
+    }
+}
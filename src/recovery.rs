@@ -0,0 +1,1248 @@
+use crate::buffer::{BufferPoolError, BufferPoolManager, Periodic, PoolAlgorithm, WritePageGuard};
+use crate::disk::{DiskManager, PageId, CHECKSUM_SIZE};
+use crate::page::PAGE_HEADER_SIZE;
+use crate::store::{FileStore, PageStore};
+use crate::wal::{Lsn, Wal, WalError};
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use thiserror::Error;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+pub struct TxnId(pub u64);
+
+// Ordered by how far a transaction got; analysis keeps the furthest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TxnStatus {
+    Running,
+    Committed,
+    Aborting,
+}
+
+// Every transaction record names its transaction and links to the
+// transaction's previous record. Updates are physical: byte ranges of a page
+// with their before and after images. A compensation record (CLR) logs the undo
+// of an update and points past it with `undo_next`, so that an interrupted
+// rollback never undoes the same update twice.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogRecord {
+    Begin {
+        txn: TxnId,
+    },
+    Update {
+        txn: TxnId,
+        prev_lsn: Lsn,
+        page_id: PageId,
+        offset: u32,
+        images: UpdateImages,
+    },
+    Commit {
+        txn: TxnId,
+        prev_lsn: Lsn,
+    },
+    Abort {
+        txn: TxnId,
+        prev_lsn: Lsn,
+    },
+    Clr {
+        txn: TxnId,
+        prev_lsn: Lsn,
+        page_id: PageId,
+        offset: u32,
+        after: Vec<u8>,
+        undo_next: Lsn,
+    },
+    End {
+        txn: TxnId,
+        prev_lsn: Lsn,
+    },
+    CheckpointBegin,
+    // The transaction table and dirty page table as of some point after the
+    // matching `CheckpointBegin`. Records in between may or may not be
+    // reflected in them.
+    CheckpointEnd {
+        txns: Vec<(TxnId, Lsn, TxnStatus)>,
+        dirty_pages: Vec<(PageId, Lsn)>,
+        next_txn: TxnId,
+    },
+    // The whole page before its first change since the last checkpoint or
+    // since it was last written back. Redo installs it in place of the page
+    // on disk, which a crash during the write-back may have torn.
+    PageImage {
+        page_id: PageId,
+        image: Vec<u8>,
+    },
+}
+
+// The bytes an update replaces and the bytes it writes, which always have the
+// same length.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdateImages {
+    // The before image followed by the after image.
+    bytes: Vec<u8>,
+}
+
+impl UpdateImages {
+    pub fn new(before: &[u8], after: &[u8]) -> Option<Self> {
+        (before.len() == after.len()).then(|| Self {
+            bytes: [before, after].concat(),
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.bytes.len() / 2
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+    pub fn before(&self) -> &[u8] {
+        &self.bytes[..self.len()]
+    }
+    pub fn after(&self) -> &[u8] {
+        &self.bytes[self.len()..]
+    }
+}
+
+const TAG_BEGIN: u8 = 1;
+const TAG_UPDATE: u8 = 2;
+const TAG_COMMIT: u8 = 3;
+const TAG_ABORT: u8 = 4;
+const TAG_CLR: u8 = 5;
+const TAG_END: u8 = 6;
+const TAG_CHECKPOINT_BEGIN: u8 = 7;
+const TAG_CHECKPOINT_END: u8 = 8;
+const TAG_PAGE_IMAGE: u8 = 9;
+
+impl LogRecord {
+    pub fn txn(&self) -> Option<TxnId> {
+        match *self {
+            Self::Begin { txn }
+            | Self::Update { txn, .. }
+            | Self::Commit { txn, .. }
+            | Self::Abort { txn, .. }
+            | Self::Clr { txn, .. }
+            | Self::End { txn, .. } => Some(txn),
+            Self::CheckpointBegin | Self::CheckpointEnd { .. } | Self::PageImage { .. } => None,
+        }
+    }
+
+    // Layout: [tag: u8], then for transaction records [txn: u64]
+    // [prev_lsn: u64], followed for updates by [page_id: u64][offset: u32]
+    // [len: u32][before][after], and for CLRs by [page_id: u64][offset: u32]
+    // [undo_next: u64][len: u32][after]. A checkpoint end holds
+    // [count: u32]([txn: u64][last_lsn: u64][status: u8])*
+    // [count: u32]([page_id: u64][rec_lsn: u64])*[next_txn: u64], and a page
+    // image [page_id: u64][len: u32][image].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let (tag, prev_lsn) = match *self {
+            Self::Begin { .. } => (TAG_BEGIN, Lsn::INVALID),
+            Self::Update { prev_lsn, .. } => (TAG_UPDATE, prev_lsn),
+            Self::Commit { prev_lsn, .. } => (TAG_COMMIT, prev_lsn),
+            Self::Abort { prev_lsn, .. } => (TAG_ABORT, prev_lsn),
+            Self::Clr { prev_lsn, .. } => (TAG_CLR, prev_lsn),
+            Self::End { prev_lsn, .. } => (TAG_END, prev_lsn),
+            Self::CheckpointBegin => (TAG_CHECKPOINT_BEGIN, Lsn::INVALID),
+            Self::CheckpointEnd { .. } => (TAG_CHECKPOINT_END, Lsn::INVALID),
+            Self::PageImage { .. } => (TAG_PAGE_IMAGE, Lsn::INVALID),
+        };
+        buf.push(tag);
+        if let Some(txn) = self.txn() {
+            buf.extend_from_slice(&txn.0.to_le_bytes());
+            buf.extend_from_slice(&prev_lsn.0.to_le_bytes());
+        }
+
+        match self {
+            Self::Update {
+                page_id,
+                offset,
+                images,
+                ..
+            } => {
+                buf.extend_from_slice(&page_id.0.to_le_bytes());
+                buf.extend_from_slice(&offset.to_le_bytes());
+                buf.extend_from_slice(&(images.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&images.bytes);
+            }
+            Self::Clr {
+                page_id,
+                offset,
+                after,
+                undo_next,
+                ..
+            } => {
+                buf.extend_from_slice(&page_id.0.to_le_bytes());
+                buf.extend_from_slice(&offset.to_le_bytes());
+                buf.extend_from_slice(&undo_next.0.to_le_bytes());
+                buf.extend_from_slice(&(after.len() as u32).to_le_bytes());
+                buf.extend_from_slice(after);
+            }
+            Self::CheckpointEnd {
+                txns,
+                dirty_pages,
+                next_txn,
+            } => {
+                buf.extend_from_slice(&(txns.len() as u32).to_le_bytes());
+                for &(txn, last_lsn, status) in txns {
+                    buf.extend_from_slice(&txn.0.to_le_bytes());
+                    buf.extend_from_slice(&last_lsn.0.to_le_bytes());
+                    buf.push(status as u8);
+                }
+                buf.extend_from_slice(&(dirty_pages.len() as u32).to_le_bytes());
+                for &(page_id, rec_lsn) in dirty_pages {
+                    buf.extend_from_slice(&page_id.0.to_le_bytes());
+                    buf.extend_from_slice(&rec_lsn.0.to_le_bytes());
+                }
+                buf.extend_from_slice(&next_txn.0.to_le_bytes());
+            }
+            Self::PageImage { page_id, image } => {
+                buf.extend_from_slice(&page_id.0.to_le_bytes());
+                buf.extend_from_slice(&(image.len() as u32).to_le_bytes());
+                buf.extend_from_slice(image);
+            }
+            _ => {}
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut reader = Reader { buf };
+        let tag = reader.bytes(1)?[0];
+        if tag == TAG_CHECKPOINT_BEGIN {
+            return reader.buf.is_empty().then_some(Self::CheckpointBegin);
+        }
+        if tag == TAG_PAGE_IMAGE {
+            let page_id = PageId(reader.u64()?);
+            let len = reader.u32()? as usize;
+            let record = Self::PageImage {
+                page_id,
+                image: reader.bytes(len)?.to_vec(),
+            };
+            return reader.buf.is_empty().then_some(record);
+        }
+        if tag == TAG_CHECKPOINT_END {
+            let txns = (0..reader.u32()?)
+                .map(|_| {
+                    let txn = TxnId(reader.u64()?);
+                    let last_lsn = Lsn(reader.u64()?);
+                    let status = match reader.bytes(1)?[0] {
+                        0 => TxnStatus::Running,
+                        1 => TxnStatus::Committed,
+                        2 => TxnStatus::Aborting,
+                        _ => return None,
+                    };
+                    Some((txn, last_lsn, status))
+                })
+                .collect::<Option<_>>()?;
+            let dirty_pages = (0..reader.u32()?)
+                .map(|_| Some((PageId(reader.u64()?), Lsn(reader.u64()?))))
+                .collect::<Option<_>>()?;
+            let next_txn = TxnId(reader.u64()?);
+            let record = Self::CheckpointEnd {
+                txns,
+                dirty_pages,
+                next_txn,
+            };
+            return reader.buf.is_empty().then_some(record);
+        }
+
+        let txn = TxnId(reader.u64()?);
+        let prev_lsn = Lsn(reader.u64()?);
+        let record = match tag {
+            TAG_BEGIN => Self::Begin { txn },
+            TAG_UPDATE => {
+                let page_id = PageId(reader.u64()?);
+                let offset = reader.u32()?;
+                let len = reader.u32()? as usize;
+                Self::Update {
+                    txn,
+                    prev_lsn,
+                    page_id,
+                    offset,
+                    images: UpdateImages {
+                        bytes: reader.bytes(2 * len)?.to_vec(),
+                    },
+                }
+            }
+            TAG_COMMIT => Self::Commit { txn, prev_lsn },
+            TAG_ABORT => Self::Abort { txn, prev_lsn },
+            TAG_CLR => {
+                let page_id = PageId(reader.u64()?);
+                let offset = reader.u32()?;
+                let undo_next = Lsn(reader.u64()?);
+                let len = reader.u32()? as usize;
+                Self::Clr {
+                    txn,
+                    prev_lsn,
+                    page_id,
+                    offset,
+                    after: reader.bytes(len)?.to_vec(),
+                    undo_next,
+                }
+            }
+            TAG_END => Self::End { txn, prev_lsn },
+            _ => return None,
+        };
+        reader.buf.is_empty().then_some(record)
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(bytes)
+    }
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RecoveryError {
+    #[error(transparent)]
+    BufferPool(#[from] BufferPoolError),
+
+    #[error(transparent)]
+    Wal(#[from] WalError),
+
+    #[error("log record at {0:?} is malformed")]
+    MalformedRecord(Lsn),
+
+    #[error("transaction {0:?} is not active")]
+    UnknownTransaction(TxnId),
+
+    #[error("{len} bytes at offset {offset} do not fit in the page body")]
+    OutOfBounds { offset: usize, len: usize },
+}
+
+// Whether the page has to be logged in full before it is changed: on its
+// first change after each checkpoint, and on its first change since it was
+// written back. The latter covers pages that stay dirty across a checkpoint
+// and are torn later; their image sits at their `rec_lsn`, which checkpoints
+// keep in the log.
+fn needs_image<L: PageStore>(wal: &Wal<L>, page: &WritePageGuard) -> bool {
+    page.rec_lsn() == Lsn::INVALID || page.page_lsn() < wal.checkpoint_lsn()
+}
+
+// The bytes that a change of `len` bytes at `offset` covers, if they lie in
+// the page body: after the page header and before the checksum trailer.
+fn body_range<S: PageStore>(
+    disk: &DiskManager<S>,
+    offset: usize,
+    len: usize,
+) -> Option<Range<usize>> {
+    let mut body_end = disk.get_page_size() as usize;
+    if disk.has_checksums() {
+        body_end -= CHECKSUM_SIZE;
+    }
+    let end = offset.checked_add(len)?;
+    (offset >= PAGE_HEADER_SIZE && end <= body_end).then_some(offset..end)
+}
+
+fn read_record<L: PageStore>(wal: &Wal<L>, lsn: Lsn) -> Result<LogRecord, RecoveryError> {
+    LogRecord::decode(&wal.read(lsn)?).ok_or(RecoveryError::MalformedRecord(lsn))
+}
+
+// Undoes the record at `lsn` of a transaction whose latest record is at
+// `last`. Records are appended through `log`, which also gets the change that
+// goes with the record to apply once it knows the record's LSN. Returns the
+// new latest record and the next record to undo, which is `Lsn::INVALID` once
+// the transaction is rolled back and ended.
+fn undo_step<Alg: PoolAlgorithm, S: PageStore, L: PageStore>(
+    bpm: &BufferPoolManager<Alg, S>,
+    wal: &Wal<L>,
+    lsn: Lsn,
+    last: Lsn,
+    log: &mut impl FnMut(LogRecord, &mut dyn FnMut(Lsn)) -> Lsn,
+) -> Result<(Lsn, Lsn), RecoveryError> {
+    let record = read_record(wal, lsn)?;
+    let txn = record.txn().ok_or(RecoveryError::MalformedRecord(lsn))?;
+    let (last, next) = match record {
+        LogRecord::Update {
+            prev_lsn,
+            page_id,
+            offset,
+            images,
+            ..
+        } => {
+            let before = images.before();
+            let range = body_range(bpm.disk_manager(), offset as usize, before.len())
+                .ok_or(RecoveryError::MalformedRecord(lsn))?;
+            // Latch the page before logging, as in `TransactionManager::write`.
+            let mut page = bpm.fetch_page(page_id)?.write();
+            if needs_image(wal, &page) {
+                let image = LogRecord::PageImage {
+                    page_id,
+                    image: page.to_vec(),
+                };
+                log(image, &mut |lsn| page.set_page_lsn(lsn));
+            }
+            let record = LogRecord::Clr {
+                txn,
+                prev_lsn: last,
+                page_id,
+                offset,
+                after: before.to_vec(),
+                undo_next: prev_lsn,
+            };
+            let clr_lsn = log(record, &mut |lsn| {
+                page[range.clone()].copy_from_slice(before);
+                page.set_page_lsn(lsn);
+            });
+            (clr_lsn, prev_lsn)
+        }
+        LogRecord::Clr { undo_next, .. } => (last, undo_next),
+        LogRecord::Commit { prev_lsn, .. }
+        | LogRecord::Abort { prev_lsn, .. }
+        | LogRecord::End { prev_lsn, .. } => (last, prev_lsn),
+        LogRecord::Begin { .. }
+        | LogRecord::CheckpointBegin
+        | LogRecord::CheckpointEnd { .. }
+        | LogRecord::PageImage { .. } => (last, Lsn::INVALID),
+    };
+
+    if next == Lsn::INVALID {
+        log(
+            LogRecord::End {
+                txn,
+                prev_lsn: last,
+            },
+            &mut |_| {},
+        );
+    }
+    Ok((last, next))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    // Page images, updates and CLRs reapplied by redo.
+    pub redone: usize,
+    // Transactions that were rolled back, in no particular order.
+    pub losers: Vec<TxnId>,
+    // Larger than any transaction id in the log.
+    pub next_txn: TxnId,
+}
+
+// Brings the pages of `bpm` back to the state described by `wal`, which must
+// be the log attached to the pool. Analysis rebuilds the transaction table and
+// dirty page table from the last checkpoint and the log after it, redo repeats
+// history from the oldest change that may not have reached disk, and undo
+// rolls back the transactions that neither committed nor finished aborting.
+// Running it again, even after a crash during recovery, is harmless: redo
+// skips changes already on a page, and undo resumes from the CLRs it logged.
+pub fn recover<Alg: PoolAlgorithm, S: PageStore, L: PageStore>(
+    bpm: &BufferPoolManager<Alg, S>,
+    wal: &Wal<L>,
+) -> Result<RecoveryReport, RecoveryError> {
+    // Analysis.
+    let mut txns: HashMap<TxnId, (Lsn, TxnStatus)> = HashMap::new();
+    // A checkpoint may still list a transaction that ended before it.
+    let mut ended = HashSet::new();
+    let mut dirty_pages: HashMap<PageId, Lsn> = HashMap::new();
+    let mut next_txn = TxnId(1);
+    let note = |txns: &mut HashMap<_, _>, txn, last: Lsn, status: TxnStatus| {
+        let entry = txns.entry(txn).or_insert((last, status));
+        *entry = (entry.0.max(last), entry.1.max(status));
+    };
+
+    let start = match wal.checkpoint_lsn() {
+        Lsn::INVALID => wal.first_lsn(),
+        checkpoint => checkpoint,
+    };
+    for record in wal.records(start) {
+        let (lsn, payload) = record?;
+        let record = LogRecord::decode(&payload).ok_or(RecoveryError::MalformedRecord(lsn))?;
+        if let Some(txn) = record.txn() {
+            next_txn = next_txn.max(TxnId(txn.0 + 1));
+        }
+
+        match record {
+            LogRecord::CheckpointBegin => {}
+            LogRecord::CheckpointEnd {
+                txns: active,
+                dirty_pages: dirty,
+                next_txn: checkpoint_next_txn,
+            } => {
+                for (txn, last, status) in active {
+                    if !ended.contains(&txn) {
+                        note(&mut txns, txn, last, status);
+                    }
+                }
+                for (page_id, rec_lsn) in dirty {
+                    let entry = dirty_pages.entry(page_id).or_insert(rec_lsn);
+                    *entry = (*entry).min(rec_lsn);
+                }
+                next_txn = next_txn.max(checkpoint_next_txn);
+            }
+            LogRecord::End { txn, .. } => {
+                txns.remove(&txn);
+                ended.insert(txn);
+            }
+            LogRecord::Commit { txn, .. } => note(&mut txns, txn, lsn, TxnStatus::Committed),
+            LogRecord::Abort { txn, .. } => note(&mut txns, txn, lsn, TxnStatus::Aborting),
+            LogRecord::Update { txn, page_id, .. } | LogRecord::Clr { txn, page_id, .. } => {
+                dirty_pages.entry(page_id).or_insert(lsn);
+                note(&mut txns, txn, lsn, TxnStatus::Running);
+            }
+            LogRecord::Begin { txn } => note(&mut txns, txn, lsn, TxnStatus::Running),
+            LogRecord::PageImage { page_id, .. } => {
+                dirty_pages.entry(page_id).or_insert(lsn);
+            }
+        }
+    }
+
+    // Redo.
+    let mut redone = 0;
+    if let Some(&start) = dirty_pages.values().min() {
+        for record in wal.records(start) {
+            let (lsn, payload) = record?;
+            let record = LogRecord::decode(&payload).ok_or(RecoveryError::MalformedRecord(lsn))?;
+            let page_id = match record {
+                LogRecord::Update { page_id, .. }
+                | LogRecord::Clr { page_id, .. }
+                | LogRecord::PageImage { page_id, .. } => page_id,
+                _ => continue,
+            };
+            if dirty_pages
+                .get(&page_id)
+                .is_none_or(|&rec_lsn| lsn < rec_lsn)
+            {
+                continue;
+            }
+
+            let change = match &record {
+                LogRecord::Update { offset, images, .. } => Some((*offset, images.after())),
+                LogRecord::Clr { offset, after, .. } => Some((*offset, &after[..])),
+                _ => None,
+            };
+            let mut page = match (&record, change) {
+                (_, Some((offset, after))) => {
+                    let range = body_range(bpm.disk_manager(), offset as usize, after.len())
+                        .ok_or(RecoveryError::MalformedRecord(lsn))?;
+                    let mut page = bpm.fetch_page(page_id)?.write();
+                    if page.page_lsn() >= lsn {
+                        continue;
+                    }
+                    page[range].copy_from_slice(after);
+                    page
+                }
+                // The page on disk may be torn and fail its checksum, so it
+                // is not read at all.
+                (LogRecord::PageImage { image, .. }, None) => {
+                    if image.len() as u64 != bpm.disk_manager().get_page_size() {
+                        return Err(RecoveryError::MalformedRecord(lsn));
+                    }
+                    bpm.restore_page(page_id, image)?
+                }
+                _ => unreachable!(),
+            };
+            page.set_page_lsn(lsn);
+            redone += 1;
+        }
+    }
+
+    // Undo, latest change first across all losers.
+    let mut losers = Vec::new();
+    let mut to_undo = BinaryHeap::new();
+    for (&txn, &(last, status)) in &txns {
+        if status == TxnStatus::Committed {
+            wal.append(
+                &LogRecord::End {
+                    txn,
+                    prev_lsn: last,
+                }
+                .encode(),
+            );
+        } else {
+            losers.push(txn);
+            to_undo.push((last, last));
+        }
+    }
+    let mut log = |record: LogRecord, apply: &mut dyn FnMut(Lsn)| {
+        let lsn = wal.append(&record.encode());
+        apply(lsn);
+        lsn
+    };
+    while let Some((lsn, last)) = to_undo.pop() {
+        let (last, next) = undo_step(bpm, wal, lsn, last, &mut log)?;
+        if next != Lsn::INVALID {
+            to_undo.push((next, last));
+        }
+    }
+    wal.flush_all()?;
+    // Redo dirties pages without logging images of them. Write them back
+    // before a checkpoint can truncate the images they were recovered from.
+    bpm.flush_all()?;
+
+    Ok(RecoveryReport {
+        redone,
+        losers,
+        next_txn,
+    })
+}
+
+// Runs transactions against a buffer pool and its log. There is no lock
+// manager: concurrent transactions must not touch the same bytes. Page
+// allocation is not logged, so pages must be durably allocated before a
+// transaction writes to them.
+pub struct TransactionManager<
+    Alg: PoolAlgorithm,
+    S: PageStore = FileStore,
+    L: PageStore = FileStore,
+> {
+    bpm: Arc<BufferPoolManager<Alg, S>>,
+    wal: Arc<Wal<L>>,
+    next_txn: AtomicU64,
+    // Changes are logged and applied to their page under this lock, which a
+    // checkpoint takes to snapshot the table.
+    active: Mutex<HashMap<TxnId, ActiveTxn>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ActiveTxn {
+    first: Lsn,
+    last: Lsn,
+    status: TxnStatus,
+}
+
+impl<Alg: PoolAlgorithm, S: PageStore, L: PageStore> TransactionManager<Alg, S, L> {
+    // Recovers the pool from `wal`, which must be the log attached to it, and
+    // returns a manager for new transactions along with what recovery did.
+    pub fn open(
+        bpm: Arc<BufferPoolManager<Alg, S>>,
+        wal: Arc<Wal<L>>,
+    ) -> Result<(Self, RecoveryReport), RecoveryError> {
+        let report = recover(&bpm, &wal)?;
+        let manager = Self {
+            bpm,
+            wal,
+            next_txn: AtomicU64::new(report.next_txn.0),
+            active: Mutex::default(),
+        };
+        Ok((manager, report))
+    }
+
+    pub fn begin(&self) -> TxnId {
+        let txn = TxnId(self.next_txn.fetch_add(1, Ordering::Relaxed));
+        let mut active = self.active.lock();
+        let lsn = self.wal.append(&LogRecord::Begin { txn }.encode());
+        active.insert(
+            txn,
+            ActiveTxn {
+                first: lsn,
+                last: lsn,
+                status: TxnStatus::Running,
+            },
+        );
+        txn
+    }
+
+    // Logs and applies a change to the page body, which starts after the page
+    // header.
+    pub fn write(
+        &self,
+        txn: TxnId,
+        page_id: PageId,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<Lsn, RecoveryError> {
+        let range = body_range(self.bpm.disk_manager(), offset, data.len()).ok_or(
+            RecoveryError::OutOfBounds {
+                offset,
+                len: data.len(),
+            },
+        )?;
+
+        // The page latch is held across the append so that page LSNs follow
+        // the order of the log.
+        let mut page = self.bpm.fetch_page(page_id)?.write();
+        let mut active = self.active.lock();
+        let entry = running(&mut active, txn)?;
+        if needs_image(&self.wal, &page) {
+            let image = LogRecord::PageImage {
+                page_id,
+                image: page.to_vec(),
+            };
+            let lsn = self.wal.append(&image.encode());
+            page.set_page_lsn(lsn);
+        }
+        let record = LogRecord::Update {
+            txn,
+            prev_lsn: entry.last,
+            page_id,
+            offset: offset as u32,
+            images: UpdateImages {
+                bytes: [&page[range.clone()], data].concat(),
+            },
+        };
+        let lsn = self.wal.append(&record.encode());
+        entry.last = lsn;
+        page[range].copy_from_slice(data);
+        page.set_page_lsn(lsn);
+        Ok(lsn)
+    }
+
+    // Returns once the commit is durable.
+    pub fn commit(&self, txn: TxnId) -> Result<(), RecoveryError> {
+        let mut active = self.active.lock();
+        let entry = running(&mut active, txn)?;
+        let lsn = self.wal.append(
+            &LogRecord::Commit {
+                txn,
+                prev_lsn: entry.last,
+            }
+            .encode(),
+        );
+        entry.last = lsn;
+        entry.status = TxnStatus::Committed;
+        drop(active);
+
+        if let Err(e) = self.wal.flush(lsn) {
+            // Whether the commit survives is up to recovery now; keep the
+            // transaction running so that it can still be rolled back.
+            if let Some(entry) = self.active.lock().get_mut(&txn) {
+                entry.status = TxnStatus::Running;
+            }
+            return Err(e.into());
+        }
+        let mut active = self.active.lock();
+        self.wal
+            .append(&LogRecord::End { txn, prev_lsn: lsn }.encode());
+        active.remove(&txn);
+        Ok(())
+    }
+
+    pub fn abort(&self, txn: TxnId) -> Result<(), RecoveryError> {
+        let mut active = self.active.lock();
+        let entry = running(&mut active, txn)?;
+        let mut last = self.wal.append(
+            &LogRecord::Abort {
+                txn,
+                prev_lsn: entry.last,
+            }
+            .encode(),
+        );
+        entry.last = last;
+        entry.status = TxnStatus::Aborting;
+        drop(active);
+
+        let mut log = |record: LogRecord, apply: &mut dyn FnMut(Lsn)| {
+            let mut active = self.active.lock();
+            let lsn = self.wal.append(&record.encode());
+            apply(lsn);
+            match record {
+                LogRecord::End { .. } => {
+                    active.remove(&txn);
+                }
+                LogRecord::PageImage { .. } => {}
+                _ => {
+                    if let Some(entry) = active.get_mut(&txn) {
+                        entry.last = lsn;
+                    }
+                }
+            }
+            lsn
+        };
+        let mut next = last;
+        while next != Lsn::INVALID {
+            (last, next) = undo_step(&self.bpm, &self.wal, next, last, &mut log)?;
+        }
+        Ok(())
+    }
+
+    // Takes a fuzzy checkpoint: transactions keep running and pages stay in
+    // the pool while the transaction table and dirty page table are logged.
+    // Afterwards the log is truncated to the oldest record that recovery
+    // could still need. Returns the LSN recovery will start its analysis at.
+    pub fn checkpoint(&self) -> Result<Lsn, RecoveryError> {
+        let begin = self.wal.append(&LogRecord::CheckpointBegin.encode());
+        // Every change logged before `begin` has been applied to its page by
+        // the time the lock is free, so its page is either in the dirty page
+        // table below or was written back before it.
+        let (txns, oldest_txn) = {
+            let active = self.active.lock();
+            let txns: Vec<_> = active
+                .iter()
+                .map(|(&txn, entry)| (txn, entry.last, entry.status))
+                .collect();
+            let oldest = active.values().map(|entry| entry.first).min();
+            (txns, oldest)
+        };
+        let next_txn = TxnId(self.next_txn.load(Ordering::Relaxed));
+        let dirty_pages = self.bpm.dirty_page_table();
+        let oldest_page = dirty_pages.iter().map(|&(_, rec_lsn)| rec_lsn).min();
+
+        let end = self.wal.append(
+            &LogRecord::CheckpointEnd {
+                txns,
+                dirty_pages,
+                next_txn,
+            }
+            .encode(),
+        );
+        self.wal.flush(end)?;
+        // Pages written back before the snapshot may only be in the OS cache.
+        self.bpm.sync()?;
+        self.wal.set_checkpoint(begin)?;
+
+        let keep = [Some(begin), oldest_txn, oldest_page]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap();
+        self.wal.truncate(keep)?;
+        Ok(begin)
+    }
+
+    pub fn buffer_pool(&self) -> &Arc<BufferPoolManager<Alg, S>> {
+        &self.bpm
+    }
+    pub fn wal(&self) -> &Arc<Wal<L>> {
+        &self.wal
+    }
+}
+
+fn running(
+    active: &mut HashMap<TxnId, ActiveTxn>,
+    txn: TxnId,
+) -> Result<&mut ActiveTxn, RecoveryError> {
+    active
+        .get_mut(&txn)
+        .filter(|entry| entry.status == TxnStatus::Running)
+        .ok_or(RecoveryError::UnknownTransaction(txn))
+}
+
+// Periodically takes a checkpoint. Errors are ignored; the next round tries
+// again, and until one succeeds recovery starts from an older checkpoint.
+pub struct Checkpointer {
+    worker: Periodic,
+}
+
+impl Checkpointer {
+    pub fn start<Alg, S, L>(manager: Arc<TransactionManager<Alg, S, L>>, interval: Duration) -> Self
+    where
+        Alg: PoolAlgorithm + 'static,
+        S: PageStore + 'static,
+        L: PageStore + 'static,
+    {
+        let worker = Periodic::start(interval, move || {
+            let _ = manager.checkpoint();
+        });
+        Self { worker }
+    }
+
+    // Stops the checkpointer and waits for its current checkpoint to finish.
+    pub fn stop(mut self) {
+        self.worker.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::{BackgroundWriter, BackgroundWriterOptions, ClockSweep};
+    use crate::disk::{DiskManager, DiskManagerError, DiskOptions};
+    use crate::store::{FaultHandle, FaultyStore, MemoryStore};
+
+    type Pool = BufferPoolManager<ClockSweep, FaultyStore<MemoryStore>>;
+    type Manager =
+        TransactionManager<ClockSweep, FaultyStore<MemoryStore>, FaultyStore<MemoryStore>>;
+
+    struct Database {
+        data: MemoryStore,
+        log: MemoryStore,
+    }
+
+    impl Database {
+        fn create(pages: u64) -> Self {
+            let db = Self {
+                data: MemoryStore::new(),
+                log: MemoryStore::new(),
+            };
+            let options = DiskOptions {
+                checksums: true,
+                ..DiskOptions::new(4096)
+            };
+            let disk = DiskManager::from_store_with_options(db.data.clone(), options).unwrap();
+            for _ in 0..pages {
+                disk.allocate_page().unwrap();
+            }
+            db
+        }
+
+        // Opens the database on fault-injecting stores and recovers it.
+        fn open(&self) -> (Manager, RecoveryReport, [FaultHandle; 2]) {
+            let data = FaultyStore::new(self.data.clone());
+            let log = FaultyStore::new(self.log.clone());
+            let faults = [data.handle(), log.handle()];
+            let wal = Arc::new(Wal::from_store(log).unwrap());
+            let disk = DiskManager::open_store(data).unwrap();
+            let bpm = Arc::new(Pool::new(disk, 4).with_wal(wal.clone()));
+            let (manager, report) = TransactionManager::open(bpm, wal).unwrap();
+            (manager, report, faults)
+        }
+
+        fn read(&self, page_id: PageId, offset: usize, len: usize) -> Vec<u8> {
+            let disk = DiskManager::open_store(self.data.clone()).unwrap();
+            let mut page = vec![0; 4096];
+            disk.read_page(page_id, &mut page).unwrap();
+            page[offset..offset + len].to_vec()
+        }
+    }
+
+    fn crash(manager: Manager, faults: [FaultHandle; 2]) {
+        for handle in faults {
+            handle.power_loss();
+        }
+        drop(manager);
+    }
+
+    #[test]
+    fn test_log_record_round_trip() {
+        let records = [
+            LogRecord::Begin { txn: TxnId(1) },
+            LogRecord::Update {
+                txn: TxnId(1),
+                prev_lsn: Lsn(32),
+                page_id: PageId(3),
+                offset: 100,
+                images: UpdateImages::new(&[1, 2], &[3, 4]).unwrap(),
+            },
+            LogRecord::Clr {
+                txn: TxnId(1),
+                prev_lsn: Lsn(80),
+                page_id: PageId(3),
+                offset: 100,
+                after: vec![1, 2],
+                undo_next: Lsn(32),
+            },
+            LogRecord::End {
+                txn: TxnId(1),
+                prev_lsn: Lsn(120),
+            },
+            LogRecord::CheckpointBegin,
+            LogRecord::CheckpointEnd {
+                txns: vec![
+                    (TxnId(2), Lsn(200), TxnStatus::Running),
+                    (TxnId(3), Lsn(240), TxnStatus::Aborting),
+                ],
+                dirty_pages: vec![(PageId(3), Lsn(80))],
+                next_txn: TxnId(4),
+            },
+            LogRecord::PageImage {
+                page_id: PageId(3),
+                image: vec![5; 64],
+            },
+        ];
+        for record in records {
+            let encoded = record.encode();
+            assert_eq!(LogRecord::decode(&encoded), Some(record));
+            assert_eq!(LogRecord::decode(&encoded[..encoded.len() - 1]), None);
+        }
+        assert_eq!(UpdateImages::new(&[1], &[1, 2]), None);
+    }
+
+    #[test]
+    fn test_abort_restores_before_images() {
+        let db = Database::create(1);
+        let (manager, _, _) = db.open();
+        let txn = manager.begin();
+        manager.write(txn, PageId(1), 16, b"first").unwrap();
+        manager.write(txn, PageId(1), 18, b"second").unwrap();
+        manager.abort(txn).unwrap();
+        assert!(matches!(
+            manager.commit(txn),
+            Err(RecoveryError::UnknownTransaction(_))
+        ));
+
+        let page = manager.buffer_pool().fetch_page_read(PageId(1)).unwrap();
+        assert!(page[16..24].iter().all(|&b| b == 0));
+        assert!(matches!(
+            manager.write(manager.begin(), PageId(1), 4094, b"abc"),
+            Err(RecoveryError::OutOfBounds { .. })
+        ));
+        // The checksum trailer is not part of the body either.
+        assert!(matches!(
+            manager.write(manager.begin(), PageId(1), 4090, b"abc"),
+            Err(RecoveryError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_recovery_rejects_changes_outside_the_body() {
+        let db = Database::create(1);
+        let wal = Wal::from_store(db.log.clone()).unwrap();
+        let begin = wal.append(&LogRecord::Begin { txn: TxnId(1) }.encode());
+        let update = wal.append(
+            &LogRecord::Update {
+                txn: TxnId(1),
+                prev_lsn: begin,
+                page_id: PageId(1),
+                offset: 4090,
+                images: UpdateImages::new(&[0; 8], &[1; 8]).unwrap(),
+            }
+            .encode(),
+        );
+        wal.flush_all().unwrap();
+        drop(wal);
+
+        let wal = Arc::new(Wal::from_store(db.log.clone()).unwrap());
+        let disk = DiskManager::open_store(db.data.clone()).unwrap();
+        let bpm = Arc::new(BufferPoolManager::<ClockSweep, _>::new(disk, 4).with_wal(wal.clone()));
+        assert!(matches!(
+            TransactionManager::open(bpm, wal),
+            Err(RecoveryError::MalformedRecord(lsn)) if lsn == update
+        ));
+    }
+
+    #[test]
+    fn test_recovery_redoes_winners_and_undoes_losers() {
+        let db = Database::create(3);
+        let (manager, report, faults) = db.open();
+        assert_eq!(
+            report,
+            RecoveryReport {
+                redone: 0,
+                losers: vec![],
+                next_txn: TxnId(1),
+            }
+        );
+
+        let winner = manager.begin();
+        let loser = manager.begin();
+        manager.write(winner, PageId(1), 8, b"committed").unwrap();
+        manager.write(loser, PageId(2), 8, b"lost").unwrap();
+        manager.write(loser, PageId(3), 8, b"lost").unwrap();
+        manager.commit(winner).unwrap();
+        // The loser's change to page 2 reaches the disk; the committed change
+        // to page 1 does not.
+        manager.buffer_pool().flush_page(PageId(2)).unwrap();
+        crash(manager, faults);
+        assert_eq!(db.read(PageId(1), 8, 9), [0; 9]);
+        assert_eq!(db.read(PageId(2), 8, 4), b"lost");
+
+        let (manager, report, faults) = db.open();
+        assert_eq!(report.losers, [loser]);
+        assert_eq!(report.next_txn, TxnId(3));
+        let pool = manager.buffer_pool();
+        assert_eq!(
+            &pool.fetch_page_read(PageId(1)).unwrap()[8..17],
+            b"committed"
+        );
+        assert_eq!(pool.fetch_page_read(PageId(2)).unwrap()[8..12], [0; 4]);
+        assert_eq!(pool.fetch_page_read(PageId(3)).unwrap()[8..12], [0; 4]);
+
+        // Crash again right after recovery: the next one finds nothing left
+        // to undo and ends up in the same state.
+        crash(manager, faults);
+        let (manager, report, _) = db.open();
+        assert!(report.losers.is_empty());
+        assert_eq!(manager.begin(), TxnId(3));
+        manager.buffer_pool().flush_all().unwrap();
+        drop(manager);
+        assert_eq!(db.read(PageId(1), 8, 9), b"committed");
+        assert_eq!(db.read(PageId(2), 8, 4), [0; 4]);
+        assert_eq!(db.read(PageId(3), 8, 4), [0; 4]);
+    }
+
+    #[test]
+    fn test_recovery_resumes_interrupted_rollback() {
+        let db = Database::create(1);
+        let (manager, _, faults) = db.open();
+        let txn = manager.begin();
+        manager.write(txn, PageId(1), 8, b"one").unwrap();
+        manager.write(txn, PageId(1), 16, b"two").unwrap();
+        manager.wal().flush_all().unwrap();
+
+        // Roll back the second update by hand, as if an abort crashed halfway.
+        let last = manager.active.lock()[&txn].last;
+        let wal = manager.wal();
+        let mut log = |record: LogRecord, apply: &mut dyn FnMut(Lsn)| {
+            let lsn = wal.append(&record.encode());
+            apply(lsn);
+            lsn
+        };
+        let (_, next) = undo_step(manager.buffer_pool(), wal, last, last, &mut log).unwrap();
+        manager.wal().flush_all().unwrap();
+        crash(manager, faults);
+
+        let (manager, report, _) = db.open();
+        assert_eq!(report.losers, [txn]);
+        let records: Vec<_> = manager
+            .wal()
+            .records(next)
+            .map(|record| LogRecord::decode(&record.unwrap().1).unwrap())
+            .collect();
+        let clrs = records
+            .iter()
+            .filter(|record| matches!(record, LogRecord::Clr { .. }))
+            .count();
+        // One CLR from before the crash and one from recovery.
+        assert_eq!(clrs, 2);
+        let page = manager.buffer_pool().fetch_page_read(PageId(1)).unwrap();
+        assert!(page[8..19].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_checkpoint_truncates_the_log() {
+        let db = Database::create(2);
+        let (manager, _, faults) = db.open();
+        let wal = Arc::clone(manager.wal());
+        let txn = manager.begin();
+        let early = manager.write(txn, PageId(1), 8, b"early").unwrap();
+        manager.commit(txn).unwrap();
+
+        // Page 1 is still dirty, so its update and the image before it have
+        // to stay in the log.
+        let checkpoint = manager.checkpoint().unwrap();
+        assert_eq!(wal.checkpoint_lsn(), checkpoint);
+        assert!(wal.first_lsn() < early);
+        assert!(matches!(
+            LogRecord::decode(&wal.read(wal.first_lsn()).unwrap()),
+            Some(LogRecord::PageImage { .. })
+        ));
+
+        let loser = manager.begin();
+        let lost = manager.write(loser, PageId(2), 8, b"lost").unwrap();
+        manager.buffer_pool().flush_all().unwrap();
+        manager.checkpoint().unwrap();
+        // Only the running loser holds the log back now.
+        assert!(wal.first_lsn() > early && wal.first_lsn() < lost);
+
+        let winner = manager.begin();
+        manager.write(winner, PageId(1), 20, b"late").unwrap();
+        manager.commit(winner).unwrap();
+        drop(wal);
+        crash(manager, faults);
+
+        let (manager, report, _) = db.open();
+        // The image of page 1 logged after the flush and the late update.
+        assert_eq!(report.redone, 2);
+        assert_eq!(report.losers, [loser]);
+        assert_eq!(report.next_txn, TxnId(4));
+        manager.buffer_pool().flush_all().unwrap();
+        drop(manager);
+        assert_eq!(db.read(PageId(1), 8, 5), b"early");
+        assert_eq!(db.read(PageId(1), 20, 4), b"late");
+        assert_eq!(db.read(PageId(2), 8, 4), [0; 4]);
+    }
+
+    #[test]
+    fn test_checkpointer_runs_in_background() {
+        let db = Database::create(1);
+        let (manager, _, _) = db.open();
+        let manager = Arc::new(manager);
+        let txn = manager.begin();
+        manager.write(txn, PageId(1), 8, b"data").unwrap();
+
+        let checkpointer = Checkpointer::start(Arc::clone(&manager), Duration::from_millis(1));
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while manager.wal().checkpoint_lsn() == Lsn::INVALID {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "no checkpoint was taken"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        checkpointer.stop();
+        manager.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_checkpointer_runs_beside_the_background_writer() {
+        let db = Database::create(6);
+        let (manager, _, faults) = db.open();
+        let manager = Arc::new(manager);
+        let options = BackgroundWriterOptions {
+            interval: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let writer = BackgroundWriter::start(Arc::clone(manager.buffer_pool()), options);
+        let checkpointer = Checkpointer::start(Arc::clone(&manager), Duration::from_millis(1));
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        let mut round = 0u64;
+        while round < 120 || manager.wal().checkpoint_lsn() == Lsn::INVALID {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "no checkpoint was taken"
+            );
+            let txn = manager.begin();
+            manager
+                .write(txn, PageId(1 + round % 6), 8, &round.to_le_bytes())
+                .unwrap();
+            manager.commit(txn).unwrap();
+            round += 1;
+        }
+        checkpointer.stop();
+        writer.stop();
+        let Ok(manager) = Arc::try_unwrap(manager) else {
+            panic!("the workers still hold the manager");
+        };
+        crash(manager, faults);
+
+        drop(db.open());
+        for last in round - 6..round {
+            assert_eq!(db.read(PageId(1 + last % 6), 8, 8), last.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_page_images_repair_torn_pages() {
+        let db = Database::create(1);
+        let (manager, _, faults) = db.open();
+        let txn = manager.begin();
+        manager.write(txn, PageId(1), 8, b"old").unwrap();
+        manager.commit(txn).unwrap();
+        manager.buffer_pool().flush_page(PageId(1)).unwrap();
+        manager.checkpoint().unwrap();
+
+        // Only the first change after the write-back logs an image.
+        let txn = manager.begin();
+        manager.write(txn, PageId(1), 8, b"new").unwrap();
+        manager.write(txn, PageId(1), 2048, b"tail").unwrap();
+        manager.commit(txn).unwrap();
+        let images = manager
+            .wal()
+            .records(manager.wal().first_lsn())
+            .filter(|record| {
+                let record = LogRecord::decode(&record.as_ref().unwrap().1).unwrap();
+                matches!(record, LogRecord::PageImage { .. })
+            })
+            .count();
+        assert_eq!(images, 1);
+
+        // The write-back only gets the first half of the page to disk.
+        let [data, _] = &faults;
+        data.tear_nth_write(1, 1024);
+        assert!(manager.buffer_pool().flush_page(PageId(1)).is_err());
+        crash(manager, faults);
+        let disk = DiskManager::open_store(db.data.clone()).unwrap();
+        assert!(matches!(
+            disk.read_page(PageId(1), &mut vec![0; 4096]),
+            Err(DiskManagerError::ChecksumMismatch { .. })
+        ));
+        drop(disk);
+
+        let (manager, report, _) = db.open();
+        assert_eq!(report.redone, 3);
+        drop(manager);
+        assert_eq!(db.read(PageId(1), 8, 3), b"new");
+        assert_eq!(db.read(PageId(1), 2048, 4), b"tail");
+    }
+}
@@ -0,0 +1,662 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    os::unix::prelude::FileExt,
+    sync::{Arc, Mutex, RwLock},
+};
+
+// Byte-addressed backing storage for a heap file. Like `pread`/`pwrite`, a
+// single `read_at` or `write_at` may transfer fewer bytes than requested.
+pub trait PageStore: Send + Sync {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize>;
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize>;
+    fn len(&self) -> std::io::Result<u64>;
+    fn set_len(&self, len: u64) -> std::io::Result<()>;
+    fn sync(&self) -> std::io::Result<()>;
+
+    fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // Tells the store that a byte range is no longer needed, so that it can
+    // free the space. The range then reads back as unspecified bytes; the
+    // length of the store does not change. Stores that cannot free space
+    // fail with `ErrorKind::Unsupported` and leave the bytes as they are.
+    fn discard(&self, _offset: u64, _len: u64) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    // Reads each buffer in full, stopping early only at the end of the store,
+    // and returns the number of bytes read into each. The requests must not
+    // overlap. Backends that can keep several requests in flight override
+    // these.
+    fn read_batch(&self, reads: &mut [(u64, &mut [u8])]) -> std::io::Result<Vec<usize>> {
+        reads
+            .iter_mut()
+            .map(|(offset, buf)| read_full(self, *offset, buf))
+            .collect()
+    }
+    fn write_batch(&self, writes: &[(u64, &[u8])]) -> std::io::Result<()> {
+        writes
+            .iter()
+            .try_for_each(|&(offset, buf)| write_full(self, offset, buf))
+    }
+}
+
+mod mmap;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub use mmap::MmapStore;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use uring::UringStore;
+
+// Repeats `read_at` until `buf` is full or the store ends, and returns the
+// number of bytes read.
+pub(crate) fn read_full<S: PageStore + ?Sized>(
+    store: &S,
+    offset: u64,
+    buf: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match store.read_at(offset + len as u64, &mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+// Punches a hole into the file. File systems that cannot do so fail with
+// `ErrorKind::Unsupported`.
+pub(crate) fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        // SAFETY: plain system call on a file descriptor we own.
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                mode,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, e));
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (file, offset, len);
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+pub(crate) fn write_full<S: PageStore + ?Sized>(
+    store: &S,
+    offset: u64,
+    buf: &[u8],
+) -> std::io::Result<()> {
+    let mut len = 0;
+    while len < buf.len() {
+        match store.write_at(offset + len as u64, &buf[len..]) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct FileStore {
+    file: File,
+}
+
+impl FileStore {
+    pub const fn from_file(file: File) -> Self {
+        Self { file }
+    }
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Self::from_file(file))
+    }
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::from_file(file))
+    }
+    // Opens the file with `O_DIRECT`, bypassing the kernel page cache. Every
+    // transfer must then be aligned to the device's logical block size.
+    #[cfg(target_os = "linux")]
+    pub fn from_path_direct(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        Ok(Self::from_file(file))
+    }
+    #[cfg(not(target_os = "linux"))]
+    pub fn from_path_direct(_path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl PageStore for FileStore {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write_at(buf, offset)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)
+    }
+    fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+    fn discard(&self, offset: u64, len: u64) -> std::io::Result<()> {
+        punch_hole(&self.file, offset, len)
+    }
+}
+
+// Clones share the same contents, so a store handed to a `DiskManager` can be
+// reopened after the manager is dropped, just like a file.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    data: Arc<RwLock<MemoryData>>,
+}
+
+// The contents are kept in fixed-size chunks so that discarding a range frees
+// its memory. Chunks that would only hold zeroes may be missing.
+const MEMORY_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    chunks: HashMap<u64, Box<[u8]>>,
+    len: u64,
+}
+
+// Splits `len` bytes at `offset` into pieces that each lie in one chunk, as
+// (chunk index, offset in the chunk, offset in the range, length).
+fn chunk_pieces(offset: u64, len: usize) -> impl Iterator<Item = (u64, usize, usize, usize)> {
+    let mut done = 0;
+    std::iter::from_fn(move || {
+        if done == len {
+            return None;
+        }
+        let position = offset + done as u64;
+        let start = (position % MEMORY_CHUNK_SIZE as u64) as usize;
+        let piece = (MEMORY_CHUNK_SIZE - start).min(len - done);
+        let item = (position / MEMORY_CHUNK_SIZE as u64, start, done, piece);
+        done += piece;
+        Some(item)
+    })
+}
+
+impl MemoryData {
+    // Zeroes the bytes of `len` at `offset`, dropping the chunks they cover
+    // entirely.
+    fn zero(&mut self, offset: u64, len: usize) {
+        for (index, start, _, piece) in chunk_pieces(offset, len) {
+            if piece == MEMORY_CHUNK_SIZE {
+                self.chunks.remove(&index);
+            } else if let Some(chunk) = self.chunks.get_mut(&index) {
+                chunk[start..start + piece].fill(0);
+            }
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let len = buf.len().min(data.len.saturating_sub(offset) as usize);
+        for (index, start, at, piece) in chunk_pieces(offset, len) {
+            let dest = &mut buf[at..at + piece];
+            match data.chunks.get(&index) {
+                Some(chunk) => dest.copy_from_slice(&chunk[start..start + piece]),
+                None => dest.fill(0),
+            }
+        }
+        Ok(len)
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
+        let mut data = self.data.write().unwrap();
+        for (index, start, at, piece) in chunk_pieces(offset, buf.len()) {
+            let chunk = data
+                .chunks
+                .entry(index)
+                .or_insert_with(|| vec![0; MEMORY_CHUNK_SIZE].into_boxed_slice());
+            chunk[start..start + piece].copy_from_slice(&buf[at..at + piece]);
+        }
+        if !buf.is_empty() {
+            data.len = data.len.max(offset + buf.len() as u64);
+        }
+        Ok(buf.len())
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.data.read().unwrap().len)
+    }
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        // Bytes cut off read back as zeroes if the store grows again.
+        if len < data.len {
+            let old = data.len;
+            data.zero(len, (old - len) as usize);
+        }
+        data.len = len;
+        Ok(())
+    }
+    fn sync(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+    fn discard(&self, offset: u64, len: u64) -> std::io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        let end = (offset + len).min(data.len);
+        if offset < end {
+            data.zero(offset, (end - offset) as usize);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IoEvent {
+    Read { offset: u64, len: usize },
+    Write { offset: u64, len: usize },
+    SetLen(u64),
+    Sync,
+    Discard { offset: u64, len: u64 },
+    Fault(Box<IoEvent>),
+}
+
+#[derive(Debug)]
+enum PendingOp {
+    Write(u64, Vec<u8>),
+    SetLen(u64),
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    fail_reads: bool,
+    fail_writes: bool,
+    fail_syncs: bool,
+    powered_off: bool,
+    writes: u64,
+    fail_write_at: Option<u64>,
+    tear_write_at: Option<(u64, usize)>,
+    // Writes since the last successful sync, replayed over the inner store on
+    // reads and discarded on power loss.
+    pending: Vec<PendingOp>,
+    trace: Vec<IoEvent>,
+}
+
+impl FaultState {
+    fn fault(&mut self, event: IoEvent) -> std::io::Error {
+        self.trace.push(IoEvent::Fault(Box::new(event)));
+        std::io::Error::other("injected fault")
+    }
+}
+
+// Controls the faults injected by the `FaultyStore` it was obtained from.
+#[derive(Clone, Debug)]
+pub struct FaultHandle {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultHandle {
+    pub fn fail_reads(&self, fail: bool) {
+        self.state.lock().unwrap().fail_reads = fail;
+    }
+    pub fn fail_writes(&self, fail: bool) {
+        self.state.lock().unwrap().fail_writes = fail;
+    }
+    pub fn fail_syncs(&self, fail: bool) {
+        self.state.lock().unwrap().fail_syncs = fail;
+    }
+    // Fails the `n`th write from now on (1-based) without writing anything.
+    pub fn fail_nth_write(&self, n: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.fail_write_at = Some(state.writes + n);
+    }
+    // Persists only the first `at` bytes of the `n`th write from now on and
+    // fails it. The torn part reaches the inner store right away, so it
+    // survives a power loss like a write cut short on the device.
+    pub fn tear_nth_write(&self, n: u64, at: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.tear_write_at = Some((state.writes + n, at));
+    }
+    // Drops every write that has not been synced yet. The store fails all
+    // further I/O; reopen the inner store to see what survived.
+    pub fn power_loss(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.clear();
+        state.powered_off = true;
+    }
+    pub fn unsynced_writes(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+    pub fn trace(&self) -> Vec<IoEvent> {
+        self.state.lock().unwrap().trace.clone()
+    }
+    pub fn clear_trace(&self) {
+        self.state.lock().unwrap().trace.clear();
+    }
+}
+
+// Wraps a store with scriptable faults. Writes are held back until `sync` so
+// that a simulated power loss can discard them.
+#[derive(Debug)]
+pub struct FaultyStore<S: PageStore> {
+    inner: S,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl<S: PageStore> FaultyStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            state: Arc::default(),
+        }
+    }
+    pub fn handle(&self) -> FaultHandle {
+        FaultHandle {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    fn pending_len(&self, state: &FaultState) -> std::io::Result<u64> {
+        let mut len = self.inner.len()?;
+        for op in &state.pending {
+            match op {
+                PendingOp::Write(offset, data) => len = len.max(offset + data.len() as u64),
+                PendingOp::SetLen(new_len) => len = *new_len,
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<S: PageStore> PageStore for FaultyStore<S> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let event = IoEvent::Read {
+            offset,
+            len: buf.len(),
+        };
+        if state.fail_reads || state.powered_off {
+            return Err(state.fault(event));
+        }
+        state.trace.push(event);
+
+        let len = self.pending_len(&state)?.saturating_sub(offset);
+        let len = len.min(buf.len() as u64) as usize;
+        let buf = &mut buf[..len];
+        let mut read = 0;
+        while read < buf.len() {
+            match self.inner.read_at(offset + read as u64, &mut buf[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf[read..].fill(0);
+
+        let end = offset + buf.len() as u64;
+        for op in &state.pending {
+            match op {
+                PendingOp::Write(at, data) => {
+                    let start = offset.max(*at);
+                    let stop = end.min(at + data.len() as u64);
+                    if start < stop {
+                        buf[(start - offset) as usize..(stop - offset) as usize]
+                            .copy_from_slice(&data[(start - at) as usize..(stop - at) as usize]);
+                    }
+                }
+                PendingOp::SetLen(new_len) => {
+                    if *new_len < end {
+                        buf[((*new_len).max(offset) - offset) as usize..].fill(0);
+                    }
+                }
+            }
+        }
+
+        Ok(buf.len())
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let event = IoEvent::Write {
+            offset,
+            len: buf.len(),
+        };
+        if state.fail_writes || state.powered_off {
+            return Err(state.fault(event));
+        }
+
+        state.writes += 1;
+        if state.fail_write_at == Some(state.writes) {
+            state.fail_write_at = None;
+            return Err(state.fault(event));
+        }
+        if let Some((n, at)) = state.tear_write_at {
+            if n == state.writes {
+                state.tear_write_at = None;
+                let torn = buf[..at.min(buf.len())].to_vec();
+                let mut written = 0;
+                while written < torn.len() {
+                    written += self
+                        .inner
+                        .write_at(offset + written as u64, &torn[written..])?;
+                }
+                // Keep it ordered after earlier writes that are still pending.
+                state.pending.push(PendingOp::Write(offset, torn));
+                return Err(state.fault(event));
+            }
+        }
+
+        state.trace.push(event);
+        state.pending.push(PendingOp::Write(offset, buf.to_vec()));
+        Ok(buf.len())
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        let state = self.state.lock().unwrap();
+        self.pending_len(&state)
+    }
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail_writes || state.powered_off {
+            return Err(state.fault(IoEvent::SetLen(len)));
+        }
+        state.trace.push(IoEvent::SetLen(len));
+        state.pending.push(PendingOp::SetLen(len));
+        Ok(())
+    }
+    fn sync(&self) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail_syncs || state.powered_off {
+            return Err(state.fault(IoEvent::Sync));
+        }
+        state.trace.push(IoEvent::Sync);
+
+        for op in std::mem::take(&mut state.pending) {
+            match op {
+                PendingOp::Write(offset, data) => {
+                    let mut written = 0;
+                    while written < data.len() {
+                        written += self
+                            .inner
+                            .write_at(offset + written as u64, &data[written..])?;
+                    }
+                }
+                PendingOp::SetLen(len) => self.inner.set_len(len)?,
+            }
+        }
+        self.inner.sync()
+    }
+    // Discarded bytes are unspecified anyway, so the inner store drops them
+    // right away.
+    fn discard(&self, offset: u64, len: u64) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let event = IoEvent::Discard { offset, len };
+        if state.fail_writes || state.powered_off {
+            return Err(state.fault(event));
+        }
+        state.trace.push(event);
+        self.inner.discard(offset, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store_is_shared_between_clones() {
+        let store = MemoryStore::new();
+        let clone = store.clone();
+
+        assert_eq!(store.write_at(4, &[1, 2, 3]).unwrap(), 3);
+        assert_eq!(clone.len().unwrap(), 7);
+
+        let mut buf = [0xff; 8];
+        assert_eq!(clone.read_at(2, &mut buf).unwrap(), 5);
+        assert_eq!(buf[..5], [0, 0, 1, 2, 3]);
+        assert_eq!(clone.read_at(16, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_memory_store_frees_discarded_chunks() {
+        let store = MemoryStore::new();
+        store.write_at(100, &[1; 5 * MEMORY_CHUNK_SIZE]).unwrap();
+        assert_eq!(store.data.read().unwrap().chunks.len(), 6);
+
+        store.discard(50, 3 * MEMORY_CHUNK_SIZE as u64).unwrap();
+        assert_eq!(store.data.read().unwrap().chunks.len(), 4);
+        assert_eq!(store.len().unwrap(), 100 + 5 * MEMORY_CHUNK_SIZE as u64);
+
+        let mut buf = vec![0xff; 5 * MEMORY_CHUNK_SIZE];
+        assert_eq!(store.read_at(100, &mut buf).unwrap(), buf.len());
+        let discarded = 3 * MEMORY_CHUNK_SIZE - 50;
+        assert!(buf[..discarded].iter().all(|&b| b == 0));
+        assert!(buf[discarded..].iter().all(|&b| b == 1));
+
+        // Bytes cut off by shrinking the store do not come back.
+        let end = 3 * MEMORY_CHUNK_SIZE as u64 + 100;
+        store.set_len(end).unwrap();
+        store.set_len(end + 100).unwrap();
+        let mut buf = [0xff; 150];
+        assert_eq!(store.read_at(end - 50, &mut buf).unwrap(), 150);
+        assert_eq!(buf[..50], [1; 50]);
+        assert_eq!(buf[50..], [0; 100]);
+    }
+
+    #[test]
+    fn test_faulty_store_fails_on_demand() {
+        let store = FaultyStore::new(MemoryStore::new());
+        let handle = store.handle();
+
+        store.write_at(0, &[1]).unwrap();
+        handle.fail_writes(true);
+        assert!(store.write_at(0, &[2]).is_err());
+        handle.fail_writes(false);
+
+        handle.fail_reads(true);
+        assert!(store.read_at(0, &mut [0]).is_err());
+        handle.fail_reads(false);
+
+        let mut buf = [0];
+        store.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [1]);
+    }
+
+    #[test]
+    fn test_faulty_store_scripted_write_faults() {
+        let store = FaultyStore::new(MemoryStore::new());
+        let handle = store.handle();
+
+        handle.fail_nth_write(2);
+        store.write_at(0, &[1; 4]).unwrap();
+        assert!(store.write_at(4, &[2; 4]).is_err());
+        store.write_at(4, &[3; 4]).unwrap();
+
+        handle.tear_nth_write(1, 2);
+        assert!(store.write_at(0, &[4; 4]).is_err());
+
+        let mut buf = [0; 8];
+        store.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [4, 4, 1, 1, 3, 3, 3, 3]);
+
+        // The torn part outlives a power loss that drops the rest.
+        handle.power_loss();
+        store.inner.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf[..2], [4, 4]);
+
+        assert_eq!(
+            handle.trace(),
+            vec![
+                IoEvent::Write { offset: 0, len: 4 },
+                IoEvent::Fault(Box::new(IoEvent::Write { offset: 4, len: 4 })),
+                IoEvent::Write { offset: 4, len: 4 },
+                IoEvent::Fault(Box::new(IoEvent::Write { offset: 0, len: 4 })),
+                IoEvent::Read { offset: 0, len: 8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_faulty_store_power_loss_drops_unsynced_writes() {
+        let inner = MemoryStore::new();
+        let store = FaultyStore::new(inner.clone());
+        let handle = store.handle();
+
+        store.write_at(0, &[1; 4]).unwrap();
+        store.sync().unwrap();
+        store.write_at(2, &[2; 4]).unwrap();
+        store.set_len(5).unwrap();
+        assert_eq!(store.len().unwrap(), 5);
+        assert_eq!(handle.unsynced_writes(), 2);
+
+        let mut buf = [0xff; 8];
+        assert_eq!(store.read_at(0, &mut buf).unwrap(), 5);
+        assert_eq!(buf[..5], [1, 1, 2, 2, 2]);
+
+        handle.power_loss();
+        assert!(store.read_at(0, &mut buf).is_err());
+        assert!(store.sync().is_err());
+
+        assert_eq!(inner.len().unwrap(), 4);
+        assert_eq!(inner.read_at(0, &mut buf).unwrap(), 4);
+        assert_eq!(buf[..4], [1; 4]);
+    }
+}
@@ -0,0 +1,211 @@
+use super::{punch_hole, PageStore};
+
+use std::fs::{File, OpenOptions};
+
+use memmap2::MmapMut;
+use parking_lot::RwLock;
+
+// A file accessed through a shared memory mapping, for read-mostly files.
+// Reads are copies out of the mapping and run in parallel; writes past the
+// mapping grow the file geometrically and remap it. Dirty pages reach the file
+// when the kernel writes them back or on `sync`, which calls `msync` before
+// `fsync`. The file is cut back to the bytes in use when the store is dropped;
+// after a crash it may be padded with zeroes.
+//
+// Nothing else may shrink the file while it is mapped: touching a mapped page
+// past the end of the file raises SIGBUS.
+#[derive(Debug)]
+pub struct MmapStore {
+    file: File,
+    map: RwLock<Mapping>,
+}
+
+#[derive(Debug)]
+struct Mapping {
+    // `None` while the file is empty, which cannot be mapped.
+    map: Option<MmapMut>,
+    // Bytes in use; the file and the mapping may extend past this.
+    len: u64,
+}
+
+impl Mapping {
+    fn capacity(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+}
+
+impl MmapStore {
+    pub fn from_file(file: File) -> std::io::Result<Self> {
+        let map = map_file(&file)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file,
+            map: RwLock::new(Mapping { map, len }),
+        })
+    }
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Self::from_file(file)
+    }
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::from_file(file)
+    }
+
+    fn resize(&self, map: &mut Option<MmapMut>, capacity: u64) -> std::io::Result<()> {
+        // Unmap first so that no mapped page lies past the new end. Dirty
+        // pages of a shared mapping survive the unmap.
+        let old = map.as_ref().map_or(0, |map| map.len() as u64);
+        *map = None;
+        match self
+            .file
+            .set_len(capacity)
+            .and_then(|()| map_file(&self.file))
+        {
+            Ok(resized) => {
+                *map = resized;
+                Ok(())
+            }
+            Err(e) => {
+                // Never leave the file unmapped, or it would read back as
+                // empty.
+                let _ = self.file.set_len(old);
+                *map = map_file(&self.file)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for MmapStore {
+    fn drop(&mut self) {
+        let mapping = self.map.get_mut();
+        if mapping.capacity() > mapping.len {
+            mapping.map = None;
+            let _ = self.file.set_len(mapping.len);
+        }
+    }
+}
+
+fn map_file(file: &File) -> std::io::Result<Option<MmapMut>> {
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    // SAFETY: the store owns the file and only resizes it while unmapped.
+    unsafe { MmapMut::map_mut(file) }.map(Some)
+}
+
+impl PageStore for MmapStore {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mapping = self.map.read();
+        let data = mapping.map.as_deref().unwrap_or_default();
+        let data = &data[..mapping.len as usize];
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut mapping = self.map.write();
+        let end = offset + buf.len() as u64;
+        let capacity = mapping.capacity();
+        if capacity < end {
+            self.resize(&mut mapping.map, end.max(capacity * 2))?;
+        }
+        let data = mapping.map.as_mut().expect("a non-empty file is mapped");
+        data[offset as usize..end as usize].copy_from_slice(buf);
+        mapping.len = mapping.len.max(end);
+        Ok(buf.len())
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.map.read().len)
+    }
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        // Resize the file exactly, so that bytes cut off now read back as
+        // zeroes if the store grows again.
+        let mut mapping = self.map.write();
+        self.resize(&mut mapping.map, len)?;
+        mapping.len = len;
+        Ok(())
+    }
+    fn sync(&self) -> std::io::Result<()> {
+        if let Some(data) = self.map.read().map.as_ref() {
+            data.flush()?;
+        }
+        self.file.sync_all()
+    }
+    fn discard(&self, offset: u64, len: u64) -> std::io::Result<()> {
+        // Holes read back as zeroes through the mapping too.
+        punch_hole(&self.file, offset, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{DiskManager, PageId};
+
+    #[test]
+    fn test_mmap_store_grows_and_reopens() {
+        let path = std::env::temp_dir().join(format!("reina-mmap-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let disk = DiskManager::map_path(&path, 4096).unwrap();
+        let pages: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        for (i, &page_id) in pages.iter().enumerate() {
+            disk.write_page(page_id, &vec![i as u8 + 1; 4096]).unwrap();
+        }
+        assert_eq!(disk.store().len().unwrap(), 4 * 4096);
+        disk.sync().unwrap();
+        drop(disk);
+
+        let disk = DiskManager::open_mapped(&path).unwrap();
+        let mut page = vec![0; 4096];
+        disk.read_page(pages[2], &mut page).unwrap();
+        assert_eq!(page, vec![3; 4096]);
+
+        disk.store().set_len(3 * 4096).unwrap();
+        disk.read_page(pages[2], &mut page).unwrap();
+        assert_eq!(page, vec![0; 4096]);
+        assert!(disk.read_page(PageId(4), &mut page).is_err());
+
+        let mut buf = [0; 8];
+        assert_eq!(disk.store().read_at(3 * 4096 - 4, &mut buf).unwrap(), 4);
+        assert_eq!(buf[..4], [2; 4]);
+        drop(disk);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_store_grows_geometrically() {
+        let path = std::env::temp_dir().join(format!("reina-mmap-grow-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = MmapStore::from_path(&path).unwrap();
+        for i in 0..5u8 {
+            store.write_at(i as u64 * 1000, &[i + 1; 1000]).unwrap();
+        }
+        assert_eq!(store.len().unwrap(), 5000);
+        assert_eq!(store.file.metadata().unwrap().len(), 8000);
+        let mut buf = [0; 16];
+        assert_eq!(store.read_at(4992, &mut buf).unwrap(), 8);
+
+        // A failed resize leaves the data mapped.
+        assert!(store.write_at(1 << 62, &[1; 8]).is_err());
+        assert_eq!(store.len().unwrap(), 5000);
+        assert_eq!(store.read_at(4992, &mut buf).unwrap(), 8);
+        assert_eq!(buf[..8], [5; 8]);
+        drop(store);
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 5000);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
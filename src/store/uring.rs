@@ -0,0 +1,315 @@
+use super::{punch_hole, read_full, write_full, PageStore};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::prelude::{AsRawFd, FileExt};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use parking_lot::Mutex;
+
+const QUEUE_DEPTH: usize = 64;
+// Adjacent requests are merged into one vectored request of at most this many
+// buffers; IOV_MAX is 1024 on Linux.
+const MAX_IOVECS: usize = 64;
+
+// A file accessed through io_uring. Single reads and writes go straight to
+// `pread`/`pwrite`; batches are merged into vectored requests for adjacent
+// pages and submitted together, so up to `QUEUE_DEPTH` of them are in flight.
+// Where io_uring is unavailable or forbidden, batches fall back to plain reads
+// and writes.
+pub struct UringStore {
+    file: File,
+    ring: Mutex<Option<IoUring>>,
+}
+
+fn unavailable(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM))
+}
+
+impl std::fmt::Debug for UringStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringStore")
+            .field("file", &self.file)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UringStore {
+    pub fn from_file(file: File) -> std::io::Result<Self> {
+        let ring = match IoUring::new(QUEUE_DEPTH as u32) {
+            Ok(ring) => Some(ring),
+            Err(e) if unavailable(&e) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            file,
+            ring: Mutex::new(ring),
+        })
+    }
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Self::from_file(file)
+    }
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::from_file(file)
+    }
+
+    // Submits the entries, at most `QUEUE_DEPTH` at a time, and returns the
+    // result of each, or `None` if the batch has to be done without io_uring.
+    // The memory they point to must stay valid until this returns.
+    unsafe fn submit(
+        &self,
+        entries: &[squeue::Entry],
+    ) -> std::io::Result<Option<Vec<std::io::Result<usize>>>> {
+        let mut guard = self.ring.lock();
+        let Some(ring) = guard.as_mut() else {
+            return Ok(None);
+        };
+        let mut results: Vec<_> = (0..entries.len()).map(|_| Ok(0)).collect();
+        for (chunk_index, chunk) in entries.chunks(QUEUE_DEPTH).enumerate() {
+            for (i, entry) in chunk.iter().enumerate() {
+                let entry = entry
+                    .clone()
+                    .user_data((chunk_index * QUEUE_DEPTH + i) as u64);
+                ring.submission()
+                    .push(&entry)
+                    .expect("submission queue holds a whole chunk");
+            }
+
+            let mut completed = 0;
+            while completed < chunk.len() {
+                match ring.submit_and_wait(chunk.len() - completed) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) if chunk.len() - ring.submission().len() == completed => {
+                        // Nothing is in flight. Drop the ring along with the
+                        // entries still queued, which point to memory the
+                        // caller is about to free.
+                        *guard = None;
+                        return if unavailable(&e) { Ok(None) } else { Err(e) };
+                    }
+                    Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EBUSY)) => {
+                        // The completion queue is full; reap it below.
+                    }
+                    Err(e) => {
+                        // The kernel may still use the caller's buffers, so
+                        // wait out what it took before giving up on the ring;
+                        // later batches use plain reads and writes.
+                        loop {
+                            completed += ring.completion().count();
+                            if completed >= chunk.len() - ring.submission().len() {
+                                break;
+                            }
+                            let _ = ring.submit_and_wait(1);
+                        }
+                        *guard = None;
+                        return Err(e);
+                    }
+                }
+                for completion in ring.completion() {
+                    let result = completion.result();
+                    results[completion.user_data() as usize] = if result < 0 {
+                        Err(std::io::Error::from_raw_os_error(-result))
+                    } else {
+                        Ok(result as usize)
+                    };
+                    completed += 1;
+                }
+            }
+        }
+        Ok(Some(results))
+    }
+}
+
+// Groups request indices, sorted by offset, into runs of adjacent requests.
+fn adjacent_runs(requests: impl Iterator<Item = (u64, usize)>) -> Vec<Vec<usize>> {
+    let mut requests: Vec<_> = requests.enumerate().collect();
+    requests.sort_by_key(|&(_, (offset, _))| offset);
+
+    let mut runs: Vec<Vec<usize>> = Vec::new();
+    let mut end = None;
+    for (index, (offset, len)) in requests {
+        match runs.last_mut() {
+            Some(run) if end == Some(offset) && run.len() < MAX_IOVECS => run.push(index),
+            _ => runs.push(vec![index]),
+        }
+        end = Some(offset + len as u64);
+    }
+    runs
+}
+
+// Spreads the bytes transferred by a vectored request over its buffers.
+fn distribute(run: &[usize], lens: impl Fn(usize) -> usize, mut transferred: usize) -> Vec<usize> {
+    run.iter()
+        .map(|&index| {
+            let len = transferred.min(lens(index));
+            transferred -= len;
+            len
+        })
+        .collect()
+}
+
+fn transferred(result: std::io::Result<usize>) -> std::io::Result<usize> {
+    match result {
+        // Whatever is left over is finished synchronously.
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(0),
+        result => result,
+    }
+}
+
+impl PageStore for UringStore {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write_at(buf, offset)
+    }
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)
+    }
+    fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+    fn discard(&self, offset: u64, len: u64) -> std::io::Result<()> {
+        punch_hole(&self.file, offset, len)
+    }
+
+    fn read_batch(&self, reads: &mut [(u64, &mut [u8])]) -> std::io::Result<Vec<usize>> {
+        let runs = adjacent_runs(reads.iter().map(|(offset, buf)| (*offset, buf.len())));
+        let iovecs: Vec<Vec<_>> = runs
+            .iter()
+            .map(|run| {
+                run.iter()
+                    .map(|&index| libc::iovec {
+                        iov_base: reads[index].1.as_mut_ptr().cast(),
+                        iov_len: reads[index].1.len(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let fd = types::Fd(self.file.as_raw_fd());
+        let entries: Vec<_> = runs
+            .iter()
+            .zip(&iovecs)
+            .map(|(run, iovecs)| {
+                opcode::Readv::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                    .offset(reads[run[0]].0)
+                    .build()
+            })
+            .collect();
+        // SAFETY: the buffers and iovecs outlive the call, which waits for
+        // every request to complete.
+        let Some(results) = (unsafe { self.submit(&entries) })? else {
+            return reads
+                .iter_mut()
+                .map(|(offset, buf)| read_full(self, *offset, buf))
+                .collect();
+        };
+
+        let mut lens = vec![0; reads.len()];
+        for (run, result) in runs.iter().zip(results) {
+            let done = distribute(run, |index| reads[index].1.len(), transferred(result)?);
+            for (&index, done) in run.iter().zip(done) {
+                let (offset, buf) = &mut reads[index];
+                lens[index] = done;
+                if done < buf.len() {
+                    lens[index] += read_full(self, *offset + done as u64, &mut buf[done..])?;
+                }
+            }
+        }
+        Ok(lens)
+    }
+    fn write_batch(&self, writes: &[(u64, &[u8])]) -> std::io::Result<()> {
+        let runs = adjacent_runs(writes.iter().map(|(offset, buf)| (*offset, buf.len())));
+        let iovecs: Vec<Vec<_>> = runs
+            .iter()
+            .map(|run| {
+                run.iter()
+                    .map(|&index| libc::iovec {
+                        iov_base: writes[index].1.as_ptr().cast_mut().cast(),
+                        iov_len: writes[index].1.len(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let fd = types::Fd(self.file.as_raw_fd());
+        let entries: Vec<_> = runs
+            .iter()
+            .zip(&iovecs)
+            .map(|(run, iovecs)| {
+                opcode::Writev::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                    .offset(writes[run[0]].0)
+                    .build()
+            })
+            .collect();
+        // SAFETY: as in `read_batch`.
+        let Some(results) = (unsafe { self.submit(&entries) })? else {
+            return writes
+                .iter()
+                .try_for_each(|&(offset, buf)| write_full(self, offset, buf));
+        };
+
+        for (run, result) in runs.iter().zip(results) {
+            let done = distribute(run, |index| writes[index].1.len(), transferred(result)?);
+            for (&index, done) in run.iter().zip(done) {
+                let (offset, buf) = writes[index];
+                if done < buf.len() {
+                    write_full(self, offset + done as u64, &buf[done..])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_batches(store: &UringStore) {
+        let pages: Vec<_> = (0..4u8).map(|i| vec![i + 1; 512]).collect();
+        let writes: Vec<_> = [3, 0, 1]
+            .iter()
+            .map(|&i| (i as u64 * 512, &pages[i][..]))
+            .collect();
+        store.write_batch(&writes).unwrap();
+        assert_eq!(store.len().unwrap(), 4 * 512);
+
+        let mut bufs = [[0; 512]; 4];
+        let [first, second, past_end, fourth] = &mut bufs;
+        let mut reads = vec![
+            (0, &mut first[..]),
+            (512, &mut second[..]),
+            (3 * 512, &mut fourth[..]),
+            (4 * 512, &mut past_end[..]),
+        ];
+        assert_eq!(store.read_batch(&mut reads).unwrap(), [512, 512, 512, 0]);
+        drop(reads);
+
+        assert_eq!(bufs[0][..], pages[0]);
+        assert_eq!(bufs[1][..], pages[1]);
+        assert_eq!(bufs[3][..], pages[3]);
+    }
+
+    #[test]
+    fn test_uring_store_batches() {
+        let path = std::env::temp_dir().join(format!("reina-uring-{}", std::process::id()));
+        // io_uring may be unavailable or forbidden in this environment, in
+        // which case the store falls back to plain reads and writes.
+        let store = UringStore::from_path(&path).unwrap();
+        check_batches(&store);
+
+        store.set_len(0).unwrap();
+        *store.ring.lock() = None;
+        check_batches(&store);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,542 @@
+use crate::store::{read_full, write_full, FileStore, PageStore};
+
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use thiserror::Error;
+
+// A log sequence number: the offset of a record in the log file. LSNs grow
+// with every append, and 0 never names a record.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+pub struct Lsn(pub u64);
+
+impl Lsn {
+    pub const INVALID: Lsn = Lsn(0);
+}
+
+// The log starts with a header that records where the oldest record still
+// needed is and where the last checkpoint begins. Records before the start
+// have been discarded; their LSNs are never reused.
+const WAL_MAGIC: [u8; 8] = *b"REINAWL\0";
+const WAL_VERSION: u32 = 2;
+const WAL_MAGIC_OFFSET: usize = 0;
+const WAL_VERSION_OFFSET: usize = 8;
+const WAL_START_OFFSET: usize = 16;
+const WAL_CHECKPOINT_OFFSET: usize = 24;
+const WAL_HEADER_SIZE: usize = 32;
+
+// Records are framed as [payload length: u32][crc32c of the length and
+// payload: u32][payload]. The log ends at the first record that does not
+// check out, which is where a crash tore the last write.
+const RECORD_HEADER_SIZE: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum WalError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("not a log file (bad magic number)")]
+    InvalidMagic,
+
+    #[error("unsupported log file version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("no log record at {0:?}")]
+    InvalidRecord(Lsn),
+
+    #[error("log header is corrupt")]
+    Corrupt,
+}
+
+#[derive(Debug)]
+struct Header {
+    start: Lsn,
+    checkpoint: Lsn,
+}
+
+impl Header {
+    fn write<S: PageStore>(&self, store: &S) -> std::io::Result<()> {
+        let mut header = [0; WAL_HEADER_SIZE];
+        header[WAL_MAGIC_OFFSET..WAL_MAGIC_OFFSET + 8].copy_from_slice(&WAL_MAGIC);
+        header[WAL_VERSION_OFFSET..WAL_VERSION_OFFSET + 4]
+            .copy_from_slice(&WAL_VERSION.to_le_bytes());
+        header[WAL_START_OFFSET..WAL_START_OFFSET + 8].copy_from_slice(&self.start.0.to_le_bytes());
+        header[WAL_CHECKPOINT_OFFSET..WAL_CHECKPOINT_OFFSET + 8]
+            .copy_from_slice(&self.checkpoint.0.to_le_bytes());
+        write_full(store, 0, &header)?;
+        store.sync()
+    }
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Debug)]
+struct LogState {
+    // Records appended since the last flush; the first one is at
+    // `buffer_start`.
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    // Everything before this offset is durable.
+    durable: u64,
+    // Set while a flush writes out records taken from `buffer`.
+    flushing: bool,
+}
+
+impl LogState {
+    fn end(&self) -> u64 {
+        self.buffer_start + self.buffer.len() as u64
+    }
+}
+
+// An append-only log. Appends only copy the record into memory; `flush`
+// makes records durable. Threads that ask for a flush while another one is in
+// progress wait for it and are then flushed together by one of them, so
+// concurrent commits share an fsync.
+#[derive(Debug)]
+pub struct Wal<S: PageStore = FileStore> {
+    store: S,
+    // Taken for header updates only; appends and flushes go through `state`.
+    header: Mutex<Header>,
+    state: Mutex<LogState>,
+    flushed: Condvar,
+}
+
+// What a buffer pool needs from its log: a dirty page is only written once
+// the log is durable up to its page LSN.
+pub trait LogFlush: Send + Sync {
+    fn flush(&self, lsn: Lsn) -> Result<(), WalError>;
+}
+
+impl<S: PageStore> LogFlush for Wal<S> {
+    fn flush(&self, lsn: Lsn) -> Result<(), WalError> {
+        Wal::flush(self, lsn)
+    }
+}
+
+impl Wal {
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> Result<Self, WalError> {
+        Self::from_store(FileStore::from_path(path)?)
+    }
+}
+
+impl<S: PageStore> Wal<S> {
+    // Creates a log in an empty store, or opens the log in it and drops a torn
+    // tail.
+    pub fn from_store(store: S) -> Result<Self, WalError> {
+        let header = if store.is_empty()? {
+            let header = Header {
+                start: Lsn(WAL_HEADER_SIZE as u64),
+                checkpoint: Lsn::INVALID,
+            };
+            header.write(&store)?;
+            header
+        } else {
+            let mut header = [0; WAL_HEADER_SIZE];
+            read_full(&store, 0, &mut header)?;
+            if header[WAL_MAGIC_OFFSET..WAL_MAGIC_OFFSET + 8] != WAL_MAGIC {
+                return Err(WalError::InvalidMagic);
+            }
+            let version = u32::from_le_bytes(
+                header[WAL_VERSION_OFFSET..WAL_VERSION_OFFSET + 4]
+                    .try_into()
+                    .unwrap(),
+            );
+            if version != WAL_VERSION {
+                return Err(WalError::UnsupportedVersion(version));
+            }
+            let header = Header {
+                start: Lsn(read_u64(&header, WAL_START_OFFSET)),
+                checkpoint: Lsn(read_u64(&header, WAL_CHECKPOINT_OFFSET)),
+            };
+            let len = store.len()?;
+            if header.start.0 < WAL_HEADER_SIZE as u64
+                || header.start.0 > len
+                || header.checkpoint != Lsn::INVALID
+                    && (header.checkpoint < header.start || header.checkpoint.0 > len)
+            {
+                return Err(WalError::Corrupt);
+            }
+            header
+        };
+
+        let mut end = header.start.0;
+        while let Some(payload) = read_stored(&store, end)? {
+            end += (RECORD_HEADER_SIZE + payload.len()) as u64;
+        }
+        // Appends must not leave stale bytes behind that could pass for
+        // records.
+        if store.len()? > end {
+            store.set_len(end)?;
+            store.sync()?;
+        }
+
+        Ok(Self {
+            store,
+            header: Mutex::new(header),
+            state: Mutex::new(LogState {
+                buffer: Vec::new(),
+                buffer_start: end,
+                durable: end,
+                flushing: false,
+            }),
+            flushed: Condvar::new(),
+        })
+    }
+
+    // The oldest record that has not been truncated away.
+    pub fn first_lsn(&self) -> Lsn {
+        self.header.lock().start
+    }
+    // Where the last complete checkpoint begins, or `Lsn::INVALID`.
+    pub fn checkpoint_lsn(&self) -> Lsn {
+        self.header.lock().checkpoint
+    }
+    // Records a durable checkpoint that begins at `lsn` for recovery to start
+    // from.
+    pub fn set_checkpoint(&self, lsn: Lsn) -> Result<(), WalError> {
+        assert!(self.is_durable(lsn), "checkpoint {lsn:?} is not durable");
+        let mut header = self.header.lock();
+        let previous = std::mem::replace(&mut header.checkpoint, lsn);
+        if let Err(e) = header.write(&self.store) {
+            header.checkpoint = previous;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    // Discards the records before `lsn`, which must not be needed by recovery
+    // any more: no dirty page may depend on them and no active transaction
+    // may have to undo them.
+    pub fn truncate(&self, lsn: Lsn) -> Result<(), WalError> {
+        assert!(lsn <= self.end_lsn(), "truncating past the end of the log");
+        let mut header = self.header.lock();
+        if lsn <= header.start {
+            return Ok(());
+        }
+        // The new start has to be durable before the records are gone.
+        let previous = std::mem::replace(&mut header.start, lsn);
+        if let Err(e) = header.write(&self.store) {
+            header.start = previous;
+            return Err(e.into());
+        }
+        // Records still waiting for a flush are in memory only.
+        let durable = self.state.lock().durable.min(lsn.0);
+        if durable > previous.0 {
+            match self.store.discard(previous.0, durable - previous.0) {
+                // The records stay on disk but are never read again.
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+    // The LSN the next record will get.
+    pub fn end_lsn(&self) -> Lsn {
+        Lsn(self.state.lock().end())
+    }
+    pub fn is_durable(&self, lsn: Lsn) -> bool {
+        self.state.lock().durable > lsn.0
+    }
+
+    pub fn append(&self, payload: &[u8]) -> Lsn {
+        let len = u32::try_from(payload.len()).expect("log record too large");
+        let checksum = crc32c::crc32c_append(crc32c::crc32c(&len.to_le_bytes()), payload);
+
+        let mut state = self.state.lock();
+        let lsn = Lsn(state.end());
+        state.buffer.extend_from_slice(&len.to_le_bytes());
+        state.buffer.extend_from_slice(&checksum.to_le_bytes());
+        state.buffer.extend_from_slice(payload);
+        lsn
+    }
+
+    // Makes the record at `lsn`, and every record before it, durable.
+    pub fn flush(&self, lsn: Lsn) -> Result<(), WalError> {
+        self.flush_to(lsn.0.saturating_add(1))
+    }
+    pub fn flush_all(&self) -> Result<(), WalError> {
+        self.flush_to(u64::MAX)
+    }
+    fn flush_to(&self, end: u64) -> Result<(), WalError> {
+        let mut state = self.state.lock();
+        let end = end.min(state.end());
+        while state.durable < end {
+            if state.flushing {
+                self.flushed.wait(&mut state);
+                continue;
+            }
+
+            // Write out whatever has been appended so far, including the
+            // records of threads waiting for this flush.
+            state.flushing = true;
+            let buffer = std::mem::take(&mut state.buffer);
+            let start = state.buffer_start;
+            state.buffer_start += buffer.len() as u64;
+            let result = MutexGuard::unlocked(&mut state, || {
+                write_full(&self.store, start, &buffer)?;
+                self.store.sync()
+            });
+
+            state.flushing = false;
+            self.flushed.notify_all();
+            if let Err(e) = result {
+                // Keep the records so that the next flush retries them.
+                let appended = std::mem::replace(&mut state.buffer, buffer);
+                state.buffer.extend_from_slice(&appended);
+                state.buffer_start = start;
+                return Err(e.into());
+            }
+            state.durable = state.buffer_start;
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, lsn: Lsn) -> Result<Vec<u8>, WalError> {
+        let mut state = self.state.lock();
+        // Records taken by an ongoing flush are in neither place until it is
+        // done.
+        while state.flushing && (state.durable..state.buffer_start).contains(&lsn.0) {
+            self.flushed.wait(&mut state);
+        }
+
+        if lsn.0 >= state.buffer_start {
+            let offset = (lsn.0 - state.buffer_start) as usize;
+            let Some(header) = state.buffer.get(offset..offset + RECORD_HEADER_SIZE) else {
+                return Err(WalError::InvalidRecord(lsn));
+            };
+            let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let start = offset + RECORD_HEADER_SIZE;
+            return state
+                .buffer
+                .get(start..start + len)
+                .map(<[u8]>::to_vec)
+                .ok_or(WalError::InvalidRecord(lsn));
+        }
+        drop(state);
+
+        if lsn < self.first_lsn() {
+            return Err(WalError::InvalidRecord(lsn));
+        }
+        read_stored(&self.store, lsn.0)?.ok_or(WalError::InvalidRecord(lsn))
+    }
+
+    // Iterates over the records from `from` up to the end of the log as of
+    // this call.
+    pub fn records(&self, from: Lsn) -> Records<'_, S> {
+        Records {
+            wal: self,
+            next: from,
+            end: self.end_lsn(),
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+// Reads the record stored at `offset`, or returns `None` if there is no
+// intact record there.
+fn read_stored<S: PageStore>(store: &S, offset: u64) -> std::io::Result<Option<Vec<u8>>> {
+    let mut header = [0; RECORD_HEADER_SIZE];
+    if read_full(store, offset, &mut header)? < RECORD_HEADER_SIZE {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if offset + (RECORD_HEADER_SIZE as u64) + len as u64 > store.len()? {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0; len as usize];
+    read_full(store, offset + RECORD_HEADER_SIZE as u64, &mut payload)?;
+    let computed = crc32c::crc32c_append(crc32c::crc32c(&header[..4]), &payload);
+    Ok((computed == checksum).then_some(payload))
+}
+
+pub struct Records<'a, S: PageStore> {
+    wal: &'a Wal<S>,
+    next: Lsn,
+    end: Lsn,
+}
+
+impl<S: PageStore> Iterator for Records<'_, S> {
+    type Item = Result<(Lsn, Vec<u8>), WalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let lsn = self.next;
+        match self.wal.read(lsn) {
+            Ok(payload) => {
+                self.next = Lsn(lsn.0 + (RECORD_HEADER_SIZE + payload.len()) as u64);
+                Some(Ok((lsn, payload)))
+            }
+            Err(e) => {
+                self.next = self.end;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{FaultyStore, MemoryStore};
+
+    use std::sync::Arc;
+
+    fn payloads<S: PageStore>(wal: &Wal<S>) -> Vec<Vec<u8>> {
+        wal.records(wal.first_lsn())
+            .map(|record| record.unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn test_wal_keeps_flushed_records() {
+        let inner = MemoryStore::new();
+        let store = FaultyStore::new(inner.clone());
+        let faults = store.handle();
+
+        let wal = Wal::from_store(store).unwrap();
+        let first = wal.append(b"first");
+        let second = wal.append(b"second");
+        assert!(first < second && !wal.is_durable(first));
+        assert_eq!(wal.read(second).unwrap(), b"second");
+
+        wal.flush(first).unwrap();
+        assert!(wal.is_durable(second));
+        let third = wal.append(b"third");
+        assert_eq!(wal.read(first).unwrap(), b"first");
+        assert_eq!(wal.read(third).unwrap(), b"third");
+        assert!(matches!(
+            wal.read(Lsn(second.0 + 1)),
+            Err(WalError::InvalidRecord(_))
+        ));
+
+        faults.power_loss();
+        drop(wal);
+        let wal = Wal::from_store(inner).unwrap();
+        assert_eq!(payloads(&wal), [&b"first"[..], b"second"]);
+        assert_eq!(wal.end_lsn(), third);
+        let fourth = wal.append(b"fourth");
+        assert_eq!(fourth, third);
+
+        // Pages that were never logged can carry any bytes as their page LSN.
+        wal.flush(Lsn(u64::MAX)).unwrap();
+        assert!(wal.is_durable(fourth));
+    }
+
+    #[test]
+    fn test_wal_drops_torn_tail() {
+        let store = MemoryStore::new();
+
+        let wal = Wal::from_store(store.clone()).unwrap();
+        wal.append(b"kept");
+        let torn = wal.append(&[7; 100]);
+        wal.flush_all().unwrap();
+        drop(wal);
+        store.set_len(torn.0 + 50).unwrap();
+
+        let wal = Wal::from_store(store.clone()).unwrap();
+        assert_eq!(payloads(&wal), [b"kept"]);
+        assert_eq!(store.len().unwrap(), torn.0);
+
+        // A record that fails its checksum ends the log as well.
+        let corrupt = wal.append(b"corrupt");
+        wal.flush_all().unwrap();
+        drop(wal);
+        store.write_at(corrupt.0 + 8, b"x").unwrap();
+        let wal = Wal::from_store(store).unwrap();
+        assert_eq!(payloads(&wal), [b"kept"]);
+    }
+
+    #[test]
+    fn test_wal_truncate_keeps_checkpoint() {
+        let store = MemoryStore::new();
+
+        let wal = Wal::from_store(store.clone()).unwrap();
+        let first = wal.append(b"first");
+        let second = wal.append(b"second");
+        wal.append(b"third");
+        wal.flush_all().unwrap();
+        wal.set_checkpoint(second).unwrap();
+        wal.truncate(second).unwrap();
+        wal.truncate(first).unwrap();
+
+        assert_eq!(wal.first_lsn(), second);
+        assert!(matches!(wal.read(first), Err(WalError::InvalidRecord(_))));
+        let mut discarded = [1; 8];
+        store.read_at(first.0, &mut discarded).unwrap();
+        assert_eq!(discarded, [0; 8]);
+        drop(wal);
+
+        let wal = Wal::from_store(store).unwrap();
+        assert_eq!(wal.first_lsn(), second);
+        assert_eq!(wal.checkpoint_lsn(), second);
+        assert_eq!(payloads(&wal), [&b"second"[..], b"third"]);
+    }
+
+    #[test]
+    fn test_wal_rejects_corrupt_header() {
+        let store = MemoryStore::new();
+        let wal = Wal::from_store(store.clone()).unwrap();
+        wal.append(b"record");
+        wal.flush_all().unwrap();
+        drop(wal);
+
+        let len = store.len().unwrap();
+        for start in [0, len + 1] {
+            store
+                .write_at(WAL_START_OFFSET as u64, &start.to_le_bytes())
+                .unwrap();
+            assert!(matches!(
+                Wal::from_store(store.clone()),
+                Err(WalError::Corrupt)
+            ));
+        }
+
+        store
+            .write_at(
+                WAL_START_OFFSET as u64,
+                &(WAL_HEADER_SIZE as u64).to_le_bytes(),
+            )
+            .unwrap();
+        store
+            .write_at(WAL_VERSION_OFFSET as u64, &1u32.to_le_bytes())
+            .unwrap();
+        assert!(matches!(
+            Wal::from_store(store),
+            Err(WalError::UnsupportedVersion(1))
+        ));
+    }
+
+    #[test]
+    fn test_wal_group_commit() {
+        let wal = Arc::new(Wal::from_store(MemoryStore::new()).unwrap());
+
+        let threads: Vec<_> = (0..8u8)
+            .map(|thread| {
+                let wal = Arc::clone(&wal);
+                std::thread::spawn(move || {
+                    for i in 0..16u8 {
+                        let lsn = wal.append(&[thread, i]);
+                        wal.flush(lsn).unwrap();
+                        assert!(wal.is_durable(lsn));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut records = payloads(&wal);
+        records.sort();
+        let expected: Vec<_> = (0..8u8)
+            .flat_map(|thread| (0..16u8).map(move |i| vec![thread, i]))
+            .collect();
+        assert_eq!(records, expected);
+    }
+}